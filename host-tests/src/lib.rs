@@ -0,0 +1,34 @@
+//! Host tests for geode-piano.
+//!
+//! The firmware only builds for the RP2040, so its tests can't be run with `cargo test` there.
+//! This crate includes the modules that don't depend on the hardware, with the same paths as in
+//! the firmware, so that their tests can run on the host:
+//!
+//! ```sh
+//! cd host-tests
+//! cargo test
+//! ```
+
+#[path = "../../src/history"]
+pub mod history {
+    pub mod state;
+}
+
+#[path = "../../src/looper"]
+pub mod looper {
+    pub mod state;
+}
+
+#[path = "../../src/perform"]
+pub mod perform {
+    pub mod state;
+}
+
+#[path = "../../src/pins"]
+pub mod pins {
+    pub mod map;
+    pub mod set;
+}
+
+#[path = "../../src/smf.rs"]
+pub mod smf;
@@ -28,7 +28,8 @@ DEALINGS IN THE SOFTWARE.
 
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* The last 64K are kept for data (see src/storage.rs) */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 64K
 
     /* Pick one of the two options for RAM layout     */
 
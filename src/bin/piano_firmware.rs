@@ -24,17 +24,22 @@
 
 use embassy_executor::Spawner;
 use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::Pin;
 use embassy_rp::i2c;
-use embassy_rp::peripherals::USB;
+use embassy_rp::peripherals::{I2C0, USB};
 use embassy_rp::usb::{Driver, InterruptHandler};
+use geode_piano::fnkey::{self, FnMode};
 use geode_piano::matrix;
-use geode_piano::matrix::{KeyMatrix, VelocityProfile};
+use geode_piano::matrix::{KeyMatrix, VelocityProfile, Zone};
 use geode_piano::midi;
-use geode_piano::usb::usb_task;
-use geode_piano::{blinky, pin_array, pins, unwrap};
+use geode_piano::usb::{self, usb_task};
+use geode_piano::{blinky, clock, hid, looper, pin_array, pins, storage, unwrap};
+
+/// Two MCP23017 extenders, and 12 onboard pins.
+type PinDriver = pins::TransparentPins<2, 12>;
 
 #[embassy_executor::task]
-async fn piano_task(pin_driver: pins::TransparentPins) {
+async fn piano_task(pin_driver: PinDriver) {
     use geode_piano::midi::KeyAction::*;
     use geode_piano::midi::Note::*;
 
@@ -444,11 +449,38 @@ async fn piano_task(pin_driver: pins::TransparentPins) {
         ],
     ];
 
-    let mut mat = KeyMatrix::new(col_pins, row_pins, keymap);
+    // Zones of the keyboard. For a split, give each zone a range and its own channel, e.g.
+    //
+    //     Zone { high: B2, transpose: 12, ..Zone::full(1, VelocityProfile::Linear) },
+    //     Zone { low: C3, ..Zone::full(0, VelocityProfile::Heavy) },
+    //
+    // Overlapping zones are layered.
+    let zones = unwrap(heapless::Vec::from_slice(&[Zone::full(
+        0,
+        VelocityProfile::Heavy,
+    )]))
+    .await;
+
+    // set `with_retrigger(true)` if the N1 switches stay closed until the keys are almost up
+    let mut mat = KeyMatrix::new(col_pins, row_pins, keymap).with_retrigger(false);
     mat.scan(
         pin_driver,
         matrix::Config {
-            velocity_prof: VelocityProfile::Heavy,
+            zones,
+            transpose: 0,
+            program: 0,
+            bank: 0,
+            // hold the lowest and highest keys to change settings
+            fn_mode: Some(FnMode::new(Some((A0, C8)), fnkey::default_menu)),
+            fn_pin: None,
+            // in HID mode, the lowest keys turn pages
+            hid: Some(hid::page_turner),
+            hid_mode: false,
+            // hold the lowest and highest keys while plugging in to update the firmware
+            bootsel_gesture: true,
+            wakeup: true,
+            macros: &[],
+            aftertouch: None,
         },
     )
     .await;
@@ -456,15 +488,26 @@ async fn piano_task(pin_driver: pins::TransparentPins) {
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
+    I2C0_IRQ => i2c::InterruptHandler<I2C0>;
 });
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
+    // before USB, which uses the flash ID as the serial number
+    storage::init(p.FLASH);
+
     let driver = Driver::new(p.USB, Irqs);
-    unwrap(_spawner.spawn(usb_task(driver, log::LevelFilter::Debug))).await;
+    // Set another product name (or VID/PID) here to tell boards apart, e.g.
+    //
+    //     usb::Identity { product: "Geode-Piano (stage)", ..usb::Identity::DEFAULT }
+    let identity = usb::Identity::DEFAULT;
+    unwrap(_spawner.spawn(usb_task(driver, log::LevelFilter::Debug, identity))).await;
     unwrap(_spawner.spawn(blinky::blink_task(p.PIN_25.into()))).await;
+    unwrap(_spawner.spawn(clock::clock_task())).await;
+
+    unwrap(_spawner.spawn(looper::looper_task())).await;
 
     defmt::debug!("main: init i2c");
     let sda = p.PIN_16;
@@ -473,20 +516,49 @@ async fn main(_spawner: Spawner) {
     let mut i2c_config = i2c::Config::default();
     let freq = 1_000_000;
     i2c_config.frequency = freq;
-    let i2c = i2c::I2c::new_blocking(p.I2C0, scl, sda, i2c_config);
+    // the pins are taken back for a moment to unjam the bus after errors
+    let (scl_n, sda_n) = (scl.pin(), sda.pin());
+    let i2c = i2c::I2c::new_async(p.I2C0, scl, sda, Irqs, i2c_config);
+    let bus = pins::Bus::i2c_with_recovery(i2c, scl_n, sda_n);
+
+    // MCP23S17 extenders can be used instead, over SPI, e.g.
+    //
+    //     let mut spi_config = spi::Config::default();
+    //     spi_config.frequency = 10_000_000;
+    //     let (clk, mosi, miso) = (p.PIN_2, p.PIN_3, p.PIN_4);
+    //     let spi = spi::Spi::new(p.SPI0, clk, mosi, miso, p.DMA_CH0, p.DMA_CH1, spi_config);
+    //     let cs = gpio::Output::new(gpio::AnyPin::from(p.PIN_5), gpio::Level::High);
+    //     let bus = unwrap(pins::Mcp23s17Bus::new(spi, cs).await).await;
+    //
+    // and give that `bus` to the pin driver instead.
+
+    // The extenders can also be found at startup instead of listing them, with
+    // `PinDriver::new_probed(bus, pins::Chip::Mcp23017, ...)`.
 
     defmt::debug!("main: starting transparent pin driver");
-    let pin_driver = unwrap(pins::TransparentPins::new(
-        i2c,
-        [0x20, 0x27],
-        pin_array!(
-            p.PIN_15, p.PIN_14, p.PIN_13, p.PIN_12, p.PIN_11, p.PIN_10, p.PIN_9, p.PIN_18,
-            p.PIN_19, p.PIN_20, p.PIN_21, p.PIN_22
-        ),
-        true,
-    ))
+    let extenders = [
+        pins::Extender::mcp23017(0x20),
+        pins::Extender::mcp23017(0x27),
+    ];
+    let pin_driver = unwrap(
+        PinDriver::new(
+            bus,
+            extenders,
+            pin_array!(
+                p.PIN_15, p.PIN_14, p.PIN_13, p.PIN_12, p.PIN_11, p.PIN_10, p.PIN_9, p.PIN_18,
+                p.PIN_19, p.PIN_20, p.PIN_21, p.PIN_22
+            ),
+            PinDriver::safe_pins(&extenders),
+        )
+        .await,
+    )
     .await;
 
+    // If the extenders' interrupt outputs (INTA and INTB) are all wired to a free pin, the
+    // scanner idles while no keys are down instead of polling the bus, e.g. (with `let mut`)
+    //
+    //     pin_driver.set_interrupt_pin(p.PIN_26.into());
+
     defmt::info!("main: starting piano task");
     _spawner.spawn(piano_task(pin_driver)).unwrap();
 
@@ -498,4 +570,10 @@ async fn main(_spawner: Spawner) {
             matrix::NormalState::NC,
         ))
         .unwrap();
+
+    // Extra buttons can be wired to free GPIO pins to change settings, for example:
+    //
+    //     _spawner
+    //         .spawn(matrix::button(p.PIN_7.into(), FnAction::ProgramStep(1)))
+    //         .unwrap();
 }
@@ -27,11 +27,14 @@ use embassy_executor::Spawner;
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio;
 use embassy_rp::i2c;
-use embassy_rp::peripherals::USB;
+use embassy_rp::peripherals::{I2C0, USB};
 use embassy_rp::usb::{Driver, InterruptHandler};
 use embassy_time::Timer;
-use geode_piano::usb::usb_task;
-use geode_piano::{blinky, pin_array, pins, unwrap};
+use geode_piano::usb::{self, usb_task};
+use geode_piano::{blinky, pin_array, pins, storage, unwrap};
+
+/// Two MCP23017 extenders, and 12 onboard pins.
+type PinDriver = pins::TransparentPins<2, 12>;
 
 /// Represents a connection between two pins as detected by the scanner.
 #[derive(Clone, Copy)]
@@ -43,7 +46,7 @@ struct Connection {
 }
 
 #[embassy_executor::task]
-async fn scanner_task(mut pin_driver: pins::TransparentPins) {
+async fn scanner_task(mut pin_driver: PinDriver) {
     log::info!("scanner_task: setting pins as input");
     for i in pin_driver.pins {
         unwrap(pin_driver.set_input(i)).await;
@@ -57,19 +60,21 @@ async fn scanner_task(mut pin_driver: pins::TransparentPins) {
 
         // for all outputs, use active low
         // (only one pin will be output at a time)
-        unwrap(pin_driver.write_all(0)).await;
+        unwrap(pin_driver.write_all(pins::PinSet::new())).await;
         log::info!("");
         log::info!("---");
         log::info!("STARTING SCAN...");
         for gnd_pin in pin_driver.pins {
             unwrap(pin_driver.set_output(gnd_pin)).await;
-            let input = unwrap(pin_driver.read_all()).await;
+            let input = unwrap(pin_driver.read_all().await).await;
             unwrap(pin_driver.set_input(gnd_pin)).await;
 
             // this represents the pins that are different from expected
-            let mask = input ^ (((1 << pin_driver.n_usable_pins()) - 1) ^ (1 << gnd_pin));
+            let mut idle = pins::PinSet::first(pin_driver.n_usable_pins());
+            idle.remove(gnd_pin);
+            let mask = input ^ idle;
             for input_pin in pin_driver.pins {
-                if ((1 << input_pin) & mask) != 0 && n_connections < MAX_CONNECTIONS {
+                if mask.contains(input_pin) && n_connections < MAX_CONNECTIONS {
                     connections[n_connections] = Some(Connection { gnd_pin, input_pin });
                     n_connections += 1;
                 }
@@ -99,14 +104,21 @@ async fn scanner_task(mut pin_driver: pins::TransparentPins) {
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
+    I2C0_IRQ => i2c::InterruptHandler<I2C0>;
 });
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
+    storage::init(p.FLASH);
     let driver = Driver::new(p.USB, Irqs);
-    unwrap(_spawner.spawn(usb_task(driver, log::LevelFilter::Debug))).await;
+    unwrap(_spawner.spawn(usb_task(
+        driver,
+        log::LevelFilter::Debug,
+        usb::Identity::DEFAULT,
+    )))
+    .await;
     unwrap(_spawner.spawn(blinky::blink_task(p.PIN_25.into()))).await;
 
     Timer::after_secs(2).await;
@@ -118,18 +130,29 @@ async fn main(_spawner: Spawner) {
     let mut i2c_config = i2c::Config::default();
     let freq = 100_000;
     i2c_config.frequency = freq;
-    let i2c = i2c::I2c::new_blocking(p.I2C0, scl, sda, i2c_config);
+    let i2c = i2c::I2c::new_async(p.I2C0, scl, sda, Irqs, i2c_config);
+
+    // list the extenders on the bus, to help find wrong address pins
+    let mut bus = pins::Bus::from(i2c);
+    pins::probe(&mut bus, pins::Chip::Mcp23017).await;
 
     log::info!("main: starting transparent pin driver");
-    let pin_driver = unwrap(pins::TransparentPins::new(
-        i2c,
-        [0x20, 0x27],
-        pin_array!(
-            p.PIN_15, p.PIN_14, p.PIN_13, p.PIN_12, p.PIN_11, p.PIN_10, p.PIN_9, p.PIN_18,
-            p.PIN_19, p.PIN_20, p.PIN_21, p.PIN_22
-        ),
-        true,
-    ))
+    let extenders = [
+        pins::Extender::mcp23017(0x20),
+        pins::Extender::mcp23017(0x27),
+    ];
+    let pin_driver = unwrap(
+        PinDriver::new(
+            bus,
+            extenders,
+            pin_array!(
+                p.PIN_15, p.PIN_14, p.PIN_13, p.PIN_12, p.PIN_11, p.PIN_10, p.PIN_9, p.PIN_18,
+                p.PIN_19, p.PIN_20, p.PIN_21, p.PIN_22
+            ),
+            PinDriver::safe_pins(&extenders),
+        )
+        .await,
+    )
     .await;
 
     log::info!("main: starting scanner task");
@@ -10,30 +10,40 @@ use embassy_executor::Spawner;
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio;
 use embassy_rp::i2c;
-use embassy_rp::peripherals::USB;
+use embassy_rp::peripherals::{I2C0, USB};
 use embassy_rp::usb::{Driver, InterruptHandler};
 use embassy_time::Timer;
-use geode_piano::usb::usb_task;
-use geode_piano::{blinky, pin_array, pins, unwrap};
+use geode_piano::usb::{self, usb_task};
+use geode_piano::{blinky, pin_array, pins, storage, unwrap};
+
+/// Two MCP23017 extenders, and 12 onboard pins.
+type PinDriver = pins::TransparentPins<2, 12>;
 
 #[embassy_executor::task]
-async fn read_task(mut pin_driver: pins::TransparentPins) {
+async fn read_task(mut pin_driver: PinDriver) {
     loop {
-        log::warn!("{:036b}", unwrap(pin_driver.read_all()).await);
+        log::warn!("{:036b}", unwrap(pin_driver.read_all().await).await);
         Timer::after_millis(1000).await;
     }
 }
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
+    I2C0_IRQ => i2c::InterruptHandler<I2C0>;
 });
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
+    storage::init(p.FLASH);
     let driver = Driver::new(p.USB, Irqs);
-    unwrap(_spawner.spawn(usb_task(driver, log::LevelFilter::Info))).await;
+    unwrap(_spawner.spawn(usb_task(
+        driver,
+        log::LevelFilter::Info,
+        usb::Identity::DEFAULT,
+    )))
+    .await;
     unwrap(_spawner.spawn(blinky::blink_task(p.PIN_25.into()))).await;
 
     Timer::after_secs(2).await;
@@ -45,18 +55,25 @@ async fn main(_spawner: Spawner) {
     let mut i2c_config = i2c::Config::default();
     let freq = 100_000;
     i2c_config.frequency = freq;
-    let i2c = i2c::I2c::new_blocking(p.I2C0, scl, sda, i2c_config);
+    let i2c = i2c::I2c::new_async(p.I2C0, scl, sda, Irqs, i2c_config);
 
     log::info!("main: starting transparent pin driver");
-    let mut pin_driver = unwrap(pins::TransparentPins::new(
-        i2c,
-        [0x20, 0x27],
-        pin_array!(
-            p.PIN_15, p.PIN_14, p.PIN_13, p.PIN_12, p.PIN_11, p.PIN_10, p.PIN_9, p.PIN_18,
-            p.PIN_19, p.PIN_20, p.PIN_21, p.PIN_22
-        ),
-        true,
-    ))
+    let extenders = [
+        pins::Extender::mcp23017(0x20),
+        pins::Extender::mcp23017(0x27),
+    ];
+    let mut pin_driver = unwrap(
+        PinDriver::new(
+            i2c,
+            extenders,
+            pin_array!(
+                p.PIN_15, p.PIN_14, p.PIN_13, p.PIN_12, p.PIN_11, p.PIN_10, p.PIN_9, p.PIN_18,
+                p.PIN_19, p.PIN_20, p.PIN_21, p.PIN_22
+            ),
+            PinDriver::safe_pins(&extenders),
+        )
+        .await,
+    )
     .await;
 
     log::info!("main: setting pins as input");
@@ -66,7 +83,7 @@ async fn main(_spawner: Spawner) {
     }
     log::debug!("main: setting pin 0 as output, active low");
     unwrap(pin_driver.set_output(0)).await;
-    unwrap(pin_driver.write_all(0)).await;
+    unwrap(pin_driver.write_all(pins::PinSet::new())).await;
 
     log::debug!("main: starting read task");
     _spawner.spawn(read_task(pin_driver)).unwrap();
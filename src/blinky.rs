@@ -1,17 +1,78 @@
 //! blinky task
+//!
+//! The status LED normally blinks once a second. Other tasks can change the pattern with
+//! [`set_status`], e.g. to confirm a setting in function-key mode.
 
+use embassy_futures::select::{select, Either};
 use embassy_rp::gpio::{Level, Output};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
 use embassy_time::Timer;
 
+/// Pattern shown on the status LED.
+#[derive(Clone, Copy)]
+pub enum Status {
+    /// Short blink every second.
+    Heartbeat,
+    /// Steadily on.
+    Solid,
+    /// Some quick flashes, then back to the previous pattern.
+    Confirm(u8),
+}
+
+static STATUS: Signal<ThreadModeRawMutex, Status> = Signal::new();
+
+/// Change the pattern on the status LED.
+pub fn set_status(status: Status) {
+    STATUS.signal(status);
+}
+
 #[embassy_executor::task]
 pub async fn blink_task(pin: embassy_rp::gpio::AnyPin) {
     let mut led = Output::new(pin, Level::Low);
+    // pattern to go back to after confirmation flashes
+    let mut base = Status::Heartbeat;
+    let mut status = Status::Heartbeat;
 
     loop {
-        led.set_high();
-        Timer::after_millis(100).await;
+        let pattern = async {
+            match status {
+                Status::Heartbeat => {
+                    led.set_high();
+                    Timer::after_millis(100).await;
+
+                    led.set_low();
+                    Timer::after_millis(900).await;
+                }
+                Status::Solid => {
+                    led.set_high();
+                    Timer::after_millis(1000).await;
+                }
+                Status::Confirm(n) => {
+                    for _ in 0..n {
+                        led.set_high();
+                        Timer::after_millis(50).await;
+
+                        led.set_low();
+                        Timer::after_millis(100).await;
+                    }
+                    Timer::after_millis(200).await;
+                }
+            }
+        };
 
-        led.set_low();
-        Timer::after_millis(900).await;
+        let res = select(pattern, STATUS.wait()).await;
+        match res {
+            Either::First(_) => {
+                if let Status::Confirm(_) = status {
+                    status = base;
+                }
+            }
+            Either::Second(new) => {
+                if !matches!(new, Status::Confirm(_)) {
+                    base = new;
+                }
+                status = new;
+            }
+        }
     }
 }
@@ -0,0 +1,41 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Reboot into the USB bootloader (BOOTSEL mode), to update the firmware without touching the
+//! board.
+//!
+//! This can be triggered by the serial port (see [`crate::serial`]), by SysEx (see
+//! [`crate::midi::sysex`]), or by holding the function-key gesture while powering up (see
+//! [`crate::matrix::Config::bootsel_gesture`]).
+
+use embassy_time::Timer;
+
+/// GPIO of the Pico's LED, which the bootloader blinks on activity.
+const LED_PIN: u32 = 25;
+
+/// Reboot into BOOTSEL mode.
+pub async fn reboot_to_bootsel() -> ! {
+    defmt::info!("bootsel: rebooting");
+    log::info!("rebooting to BOOTSEL mode");
+    // let the log and any replies go out first
+    Timer::after_millis(100).await;
+    embassy_rp::rom_data::reset_to_usb_boot(1 << LED_PIN, 0);
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
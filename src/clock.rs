@@ -0,0 +1,262 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tempo, MIDI clock and metronome.
+//!
+//! Ticks (24 per quarter note, like MIDI clock) either come from an internal timer at the set
+//! tempo, or from MIDI clock sent by the host. With the internal tempo, the transport
+//! (start/stop/continue) and clock are also sent to the host, so that it can follow along.
+
+use crate::blinky;
+use crate::midi;
+use crate::perform;
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+
+/// Clock ticks per quarter note.
+pub const TICKS_PER_BEAT: u32 = 24;
+
+/// MIDI real-time status bytes.
+const CLOCK: u8 = 0xf8;
+const START: u8 = 0xfa;
+const CONTINUE: u8 = 0xfb;
+const STOP: u8 = 0xfc;
+
+/// Taps further apart than this start a new tap tempo measurement.
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Length of a metronome click, in ticks.
+const CLICK_TICKS: u32 = 2;
+
+/// Internal tempo, in beats per minute.
+static TEMPO: AtomicU16 = AtomicU16::new(120);
+/// Follow MIDI clock from the host instead of the internal tempo.
+static EXTERNAL: AtomicBool = AtomicBool::new(false);
+/// Transport is started.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Ticks since the transport was started.
+static POSITION: AtomicU32 = AtomicU32::new(0);
+
+/// How beats are signalled while the transport runs.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Metronome {
+    Off,
+    /// Send a note on some channel (0-15) every beat.
+    Click {
+        channel: u8,
+        note: midi::Note,
+        velocity: u8,
+    },
+    /// Flash the status LED every beat.
+    Led,
+}
+
+static METRONOME: Mutex<ThreadModeRawMutex, Cell<Metronome>> =
+    Mutex::new(Cell::new(Metronome::Off));
+
+/// Tap tempo measurement.
+#[derive(Clone, Copy)]
+struct Taps {
+    first: Instant,
+    last: Instant,
+    count: u32,
+}
+
+static TAPS: Mutex<ThreadModeRawMutex, Cell<Option<Taps>>> = Mutex::new(Cell::new(None));
+
+/// Set the internal tempo, in beats per minute.
+pub fn set_tempo(bpm: u16) {
+    TEMPO.store(bpm.clamp(20, 300), Ordering::Relaxed);
+}
+
+/// Internal tempo, in beats per minute.
+pub fn tempo() -> u16 {
+    TEMPO.load(Ordering::Relaxed)
+}
+
+/// Choose between MIDI clock from the host (`true`) or the internal tempo (`false`).
+pub fn set_external(external: bool) {
+    EXTERNAL.store(external, Ordering::Relaxed);
+}
+
+fn external() -> bool {
+    EXTERNAL.load(Ordering::Relaxed)
+}
+
+/// Transport is started.
+pub fn running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Change how beats are signalled.
+pub fn set_metronome(metronome: Metronome) {
+    defmt::info!("clock: metronome {}", metronome);
+    METRONOME.lock(|m| m.set(metronome));
+}
+
+/// Register a tap for tap tempo. The tempo is the average over all taps in a row.
+pub fn tap() {
+    let now = Instant::now();
+    let taps = TAPS.lock(|t| {
+        let taps = match t.get() {
+            Some(taps) if now - taps.last < TAP_TIMEOUT => Taps {
+                last: now,
+                count: taps.count + 1,
+                ..taps
+            },
+            _ => Taps {
+                first: now,
+                last: now,
+                count: 1,
+            },
+        };
+        t.set(Some(taps));
+        taps
+    });
+    if taps.count >= 2 {
+        let us = (taps.last - taps.first).as_micros() / (taps.count as u64 - 1);
+        set_tempo((60_000_000 / us.max(1)).min(u16::MAX as u64) as u16);
+        defmt::info!("clock: tapped tempo {}", tempo());
+    }
+}
+
+/// Start the transport from the beginning.
+pub async fn start() {
+    if external() {
+        defmt::warn!("clock: following host clock, can not start");
+        return;
+    }
+    begin().await;
+    midi::realtime(START).await;
+}
+
+/// Start the transport from where it was stopped.
+pub async fn resume() {
+    if external() {
+        defmt::warn!("clock: following host clock, can not continue");
+        return;
+    }
+    RUNNING.store(true, Ordering::Relaxed);
+    midi::realtime(CONTINUE).await;
+}
+
+/// Stop the transport.
+pub async fn stop() {
+    if external() {
+        defmt::warn!("clock: following host clock, can not stop");
+        return;
+    }
+    halt().await;
+    midi::realtime(STOP).await;
+}
+
+/// Start the transport if stopped, or stop it.
+pub async fn toggle() {
+    if running() {
+        stop().await;
+    } else {
+        start().await;
+    }
+}
+
+async fn begin() {
+    POSITION.store(0, Ordering::Relaxed);
+    RUNNING.store(true, Ordering::Relaxed);
+    perform::restart().await;
+}
+
+async fn halt() {
+    RUNNING.store(false, Ordering::Relaxed);
+    // don't leave a click hanging
+    if let Metronome::Click { channel, note, .. } = METRONOME.lock(|m| m.get()) {
+        midi::MidiChannel::new(channel).note_off(note, 0).await;
+    }
+}
+
+/// Time between two ticks at some tempo.
+fn tick_period(bpm: u16) -> Duration {
+    Duration::from_micros(60_000_000 / (bpm as u64 * TICKS_PER_BEAT as u64))
+}
+
+/// Signal beats, given the ticks since the transport started.
+async fn metronome(position: u32) {
+    let beat = position % TICKS_PER_BEAT;
+    match METRONOME.lock(|m| m.get()) {
+        Metronome::Off => {}
+        Metronome::Click {
+            channel,
+            note,
+            velocity,
+        } => {
+            let chan = midi::MidiChannel::new(channel);
+            if beat == 0 {
+                chan.note_on(note, velocity).await;
+            } else if beat == CLICK_TICKS {
+                chan.note_off(note, 0).await;
+            }
+        }
+        Metronome::Led => {
+            if beat == 0 {
+                blinky::set_status(blinky::Status::Confirm(1));
+            }
+        }
+    }
+}
+
+/// Called on every clock tick, internal or external.
+async fn tick() {
+    let running = running();
+    if running && !external() {
+        midi::realtime(CLOCK).await;
+    }
+    perform::tick().await;
+    if running {
+        metronome(POSITION.fetch_add(1, Ordering::Relaxed)).await;
+    }
+}
+
+/// Handle a MIDI system real-time message from the host.
+pub async fn realtime(status: u8) {
+    if !external() {
+        return;
+    }
+    match status {
+        CLOCK => tick().await,
+        START => begin().await,
+        CONTINUE => RUNNING.store(true, Ordering::Relaxed),
+        STOP => halt().await,
+        _ => {}
+    }
+}
+
+/// Task generating ticks from the internal tempo.
+#[embassy_executor::task]
+pub async fn clock_task() {
+    let mut next = Instant::now();
+    loop {
+        next += tick_period(tempo());
+        // if the task was held up, skip the missed ticks rather than sending them all at once
+        next = next.max(Instant::now());
+        Timer::at(next).await;
+        if !external() {
+            tick().await;
+        }
+    }
+}
@@ -0,0 +1,281 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Function-key mode, to change settings without a computer.
+//!
+//! Holding the shift gesture turns the keyboard into a menu. The gesture is holding two keys
+//! together (usually the lowest and highest keys), or a pedal/button on a GPIO pin. While the
+//! menu is open, keys select settings instead of playing notes. See [`default_menu`] for the
+//! default layout.
+//!
+//! This module is only the state machine. It is fed the key events from the matrix scan, and
+//! tells the scanner what to do with them.
+
+use crate::clock::Metronome;
+use crate::looper;
+use crate::matrix::VelocityProfile;
+use crate::midi::Note;
+use crate::perform::{ArpPattern, PerformMode};
+
+/// Setting selected by a key in the menu.
+#[derive(Clone, Copy)]
+pub enum FnAction {
+    /// Set the velocity profile of every zone.
+    VelocityProfile(VelocityProfile),
+    /// Set the transposition of the whole keyboard, in semitones.
+    Transpose(i8),
+    /// Transpose the whole keyboard further up or down.
+    TransposeStep(i8),
+    /// Set the MIDI channel (0-15) of the first zone.
+    Channel(u8),
+    /// Go up or down some amount of programs.
+    ProgramStep(i8),
+    /// Select a program (0-127) in the current bank.
+    Program(u8),
+    /// Go up or down some amount of banks.
+    BankStep(i8),
+    /// Select a bank (0-16383).
+    Bank(u16),
+    /// Flip the polarity of the pedals.
+    PedalPolarity,
+    /// Send a Control Change (controller number, value) on every zone's channel.
+    ControlChange(u8, u8),
+    /// Switch performance mode (latch, chord memory, arpeggiator).
+    Perform(PerformMode),
+    /// Store the next chord played, for chord memory mode.
+    LearnChord,
+    /// Set the arpeggiator pattern.
+    ArpPattern(ArpPattern),
+    /// Set the internal tempo, in beats per minute.
+    Tempo(u16),
+    /// Make the internal tempo faster or slower, in beats per minute.
+    TempoStep(i8),
+    /// Tap tempo. Tap a few times in rhythm to set the tempo.
+    TapTempo,
+    /// Start or stop the transport (sends MIDI start/stop and clock).
+    Transport,
+    /// Change how beats are signalled.
+    Metronome(Metronome),
+    /// Control the looper.
+    Looper(looper::Command),
+    /// Turn HID mode (keys sending keystrokes or gamepad buttons) on or off.
+    HidMode,
+}
+
+/// What to do with a key event after passing it through [`FnMode`].
+pub enum FnEvent {
+    /// Key is not used by the menu and should be played normally.
+    Play,
+    /// Key was used by the menu, and should not be played.
+    Consumed,
+    /// Menu was opened. Notes that are currently sounding should be released.
+    Enter,
+    /// Menu was closed.
+    Exit,
+    /// Key selected a setting.
+    Action(FnAction),
+    /// Key was pressed in the menu, but has no function.
+    Unmapped,
+}
+
+/// Default menu layout, for an 88-key keyboard.
+///
+/// - C1, D1, E1: light, linear, heavy velocity profile
+/// - F1: toggle HID mode
+/// - G1, A1: tempo 5 BPM slower, faster
+/// - B1: tap tempo
+/// - C2: start/stop transport
+/// - D2, E2, F2: metronome off, LED, click (side stick on channel 10)
+/// - FS2, G2, GS2, A2: looper stop, record, overdub, play
+/// - AS2, B2: save recording to flash, load it
+/// - C3 to B4: transpose, relative to C4 (C3 is an octave down, C4 resets)
+/// - C5 to DS6: MIDI channels 1 to 16
+/// - F6, G6: previous, next bank
+/// - A6, B6: previous, next program
+/// - C7: flip pedal polarity
+/// - D7, E7, F7, G7: performance mode off, latch, chord memory, arpeggiator
+/// - CS7: learn a chord for chord memory
+/// - DS7, FS7, GS7, AS7: arpeggiator pattern up, down, up-down, random
+/// - A7: arpeggiator pattern in the order keys are pressed
+pub fn default_menu(note: Note) -> Option<FnAction> {
+    let n = note as u8;
+    match note {
+        Note::C1 => Some(FnAction::VelocityProfile(VelocityProfile::Light)),
+        Note::D1 => Some(FnAction::VelocityProfile(VelocityProfile::Linear)),
+        Note::E1 => Some(FnAction::VelocityProfile(VelocityProfile::Heavy)),
+        Note::F1 => Some(FnAction::HidMode),
+        Note::G1 => Some(FnAction::TempoStep(-5)),
+        Note::A1 => Some(FnAction::TempoStep(5)),
+        Note::B1 => Some(FnAction::TapTempo),
+        Note::C2 => Some(FnAction::Transport),
+        Note::D2 => Some(FnAction::Metronome(Metronome::Off)),
+        Note::E2 => Some(FnAction::Metronome(Metronome::Led)),
+        Note::F2 => Some(FnAction::Metronome(Metronome::Click {
+            channel: 9,
+            note: Note::CS2,
+            velocity: 100,
+        })),
+        Note::FS2 => Some(FnAction::Looper(looper::Command::Stop)),
+        Note::G2 => Some(FnAction::Looper(looper::Command::Record)),
+        Note::GS2 => Some(FnAction::Looper(looper::Command::Overdub)),
+        Note::A2 => Some(FnAction::Looper(looper::Command::Play)),
+        Note::AS2 => Some(FnAction::Looper(looper::Command::Save)),
+        Note::B2 => Some(FnAction::Looper(looper::Command::Load)),
+        Note::F6 => Some(FnAction::BankStep(-1)),
+        Note::G6 => Some(FnAction::BankStep(1)),
+        Note::A6 => Some(FnAction::ProgramStep(-1)),
+        Note::B6 => Some(FnAction::ProgramStep(1)),
+        Note::C7 => Some(FnAction::PedalPolarity),
+        Note::CS7 => Some(FnAction::LearnChord),
+        Note::D7 => Some(FnAction::Perform(PerformMode::Off)),
+        Note::E7 => Some(FnAction::Perform(PerformMode::Latch)),
+        Note::F7 => Some(FnAction::Perform(PerformMode::Chord)),
+        Note::G7 => Some(FnAction::Perform(PerformMode::Arp)),
+        Note::DS7 => Some(FnAction::ArpPattern(ArpPattern::Up)),
+        Note::FS7 => Some(FnAction::ArpPattern(ArpPattern::Down)),
+        Note::GS7 => Some(FnAction::ArpPattern(ArpPattern::UpDown)),
+        Note::AS7 => Some(FnAction::ArpPattern(ArpPattern::Random)),
+        Note::A7 => Some(FnAction::ArpPattern(ArpPattern::Order)),
+        _ if (Note::C3 as u8..=Note::B4 as u8).contains(&n) => {
+            Some(FnAction::Transpose(n as i8 - Note::C4 as i8))
+        }
+        _ if (Note::C5 as u8..=Note::DS6 as u8).contains(&n) => {
+            Some(FnAction::Channel(n - Note::C5 as u8))
+        }
+        _ => None,
+    }
+}
+
+/// Function-key mode state machine.
+pub struct FnMode {
+    /// Keys that open the menu when held together.
+    chord: Option<(Note, Note)>,
+    /// Maps keys to settings while in the menu.
+    menu: fn(Note) -> Option<FnAction>,
+    /// Which of the chord keys are held (bit 0 is the first, bit 1 the second).
+    chord_held: u8,
+    /// State of the shift pin, if there is one.
+    pin_held: bool,
+    /// Menu is open.
+    active: bool,
+    /// Bitmask (by note number) of keys that are currently held.
+    held: u128,
+    /// Bitmask of keys whose release should not be played.
+    consumed: u128,
+}
+
+impl FnMode {
+    /// New function.
+    ///
+    /// `chord` is a pair of keys that opens the menu when held together. Set it to `None` if only
+    /// the shift pin should be used. `menu` maps keys to settings, e.g. [`default_menu`].
+    pub fn new(chord: Option<(Note, Note)>, menu: fn(Note) -> Option<FnAction>) -> Self {
+        FnMode {
+            chord,
+            menu,
+            chord_held: 0,
+            pin_held: false,
+            active: false,
+            held: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Menu is currently open.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn shift_held(&self) -> bool {
+        self.pin_held || self.chord_held == 0b11
+    }
+
+    /// Open or close the menu depending on the shift gesture.
+    fn update(&mut self) -> Option<FnEvent> {
+        let shift = self.shift_held();
+        if shift && !self.active {
+            self.active = true;
+            // everything held right now was interrupted by the menu
+            self.consumed |= self.held;
+            Some(FnEvent::Enter)
+        } else if !shift && self.active {
+            self.active = false;
+            Some(FnEvent::Exit)
+        } else {
+            None
+        }
+    }
+
+    /// Update the state of the shift pin (`held` is true when pressed).
+    pub fn shift_pin(&mut self, held: bool) -> Option<FnEvent> {
+        self.pin_held = held;
+        self.update()
+    }
+
+    /// Feed a key press (`pressed` is true) or release from the matrix.
+    pub fn key(&mut self, note: Note, pressed: bool) -> FnEvent {
+        let bit = 1u128 << (note as u8);
+        if pressed {
+            self.held |= bit;
+        } else {
+            self.held &= !bit;
+        }
+
+        let mut is_chord_key = false;
+        if let Some((a, b)) = self.chord {
+            for (i, key) in [a, b].iter().enumerate() {
+                if *key as u8 == note as u8 {
+                    is_chord_key = true;
+                    if pressed {
+                        self.chord_held |= 1 << i;
+                    } else {
+                        self.chord_held &= !(1 << i);
+                    }
+                }
+            }
+        }
+
+        if let Some(ev) = self.update() {
+            if !pressed {
+                self.consumed &= !bit;
+            }
+            return ev;
+        }
+
+        if !pressed {
+            if self.consumed & bit != 0 {
+                self.consumed &= !bit;
+                return FnEvent::Consumed;
+            }
+            return FnEvent::Play;
+        }
+
+        if !self.active {
+            return FnEvent::Play;
+        }
+
+        self.consumed |= bit;
+        if is_chord_key {
+            return FnEvent::Consumed;
+        }
+        match (self.menu)(note) {
+            Some(action) => FnEvent::Action(action),
+            None => FnEvent::Unmapped,
+        }
+    }
+}
@@ -0,0 +1,219 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! USB HID keyboard and gamepad.
+//!
+//! In HID mode, keys that are in the HID map send keystrokes or gamepad buttons instead of
+//! notes, e.g. to play rhythm games, or turn pages in a score viewer. Other keys still play
+//! notes. The map is [`crate::matrix::Config::hid`], and HID mode is toggled from the
+//! function-key menu.
+
+use crate::midi::{Disconnected, Note};
+use core::cell::RefCell;
+use embassy_futures::select::{select, Either};
+use embassy_rp::usb::{Driver, Instance};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_usb::class::hid::HidWriter;
+use usbd_hid::descriptor::KeyboardUsage;
+
+/// What a key does in HID mode.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HidAction {
+    /// Keystroke: modifiers (see [`modifier`]), and usage ID on the keyboard page (e.g.
+    /// `KeyboardUsage::KeyboardSpacebar as u8`). The usage can be 0 to only hold modifiers.
+    Key(u8, u8),
+    /// Gamepad button (0-31).
+    Button(u8),
+}
+
+/// Maps keys to HID actions. Keys without an action play notes.
+pub type HidMap = fn(Note) -> Option<HidAction>;
+
+/// Keyboard modifier bits.
+pub mod modifier {
+    pub const CTRL: u8 = 1 << 0;
+    pub const SHIFT: u8 = 1 << 1;
+    pub const ALT: u8 = 1 << 2;
+    pub const GUI: u8 = 1 << 3;
+}
+
+/// Page turner: the lowest two keys go to the previous and next page.
+pub fn page_turner(note: Note) -> Option<HidAction> {
+    match note {
+        Note::A0 => Some(HidAction::Key(0, KeyboardUsage::KeyboardPageUp as u8)),
+        Note::B0 => Some(HidAction::Key(0, KeyboardUsage::KeyboardPageDown as u8)),
+        _ => None,
+    }
+}
+
+/// Rhythm games: C4, D4, E4, F4 are the D, F, J, K keys, and G4 is the space bar.
+pub fn rhythm_game(note: Note) -> Option<HidAction> {
+    let usage = match note {
+        Note::C4 => KeyboardUsage::KeyboardDd,
+        Note::D4 => KeyboardUsage::KeyboardFf,
+        Note::E4 => KeyboardUsage::KeyboardJj,
+        Note::F4 => KeyboardUsage::KeyboardKk,
+        Note::G4 => KeyboardUsage::KeyboardSpacebar,
+        _ => return None,
+    };
+    Some(HidAction::Key(0, usage as u8))
+}
+
+/// Gamepad: 32 buttons, going up by semitone from C3.
+pub fn gamepad(note: Note) -> Option<HidAction> {
+    let n = (note as u8).checked_sub(Note::C3 as u8)?;
+    (n < 32).then_some(HidAction::Button(n))
+}
+
+/// Report descriptor for a gamepad with 32 buttons.
+#[rustfmt::skip]
+pub const GAMEPAD_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // usage page (generic desktop)
+    0x09, 0x05, // usage (gamepad)
+    0xa1, 0x01, // collection (application)
+    0x05, 0x09, // usage page (button)
+    0x19, 0x01, // usage minimum (1)
+    0x29, 0x20, // usage maximum (32)
+    0x15, 0x00, // logical minimum (0)
+    0x25, 0x01, // logical maximum (1)
+    0x75, 0x01, // report size (1)
+    0x95, 0x20, // report count (32)
+    0x81, 0x02, // input (data, variable, absolute)
+    0xc0, // end collection
+];
+
+/// Report to send to the host.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Report {
+    /// Boot keyboard report: modifiers, reserved, then up to 6 usages.
+    Keyboard([u8; 8]),
+    /// Gamepad buttons, as a bitmask.
+    Gamepad([u8; 4]),
+}
+
+/// Keys and buttons held, as a plain state machine.
+pub struct HidState {
+    /// Held keystrokes (modifiers, usage).
+    keys: heapless::Vec<(u8, u8), 6>,
+    buttons: u32,
+}
+
+impl HidState {
+    pub const fn new() -> Self {
+        HidState {
+            keys: heapless::Vec::new(),
+            buttons: 0,
+        }
+    }
+
+    fn keyboard_report(&self) -> Report {
+        let mut report = [0u8; 8];
+        for (i, (mods, usage)) in self.keys.iter().enumerate() {
+            report[0] |= mods;
+            report[2 + i] = *usage;
+        }
+        Report::Keyboard(report)
+    }
+
+    fn gamepad_report(&self) -> Report {
+        Report::Gamepad(self.buttons.to_le_bytes())
+    }
+
+    /// Press a key or button. Returns the report to send.
+    pub fn press(&mut self, action: HidAction) -> Report {
+        match action {
+            HidAction::Key(mods, usage) => {
+                if self.keys.push((mods, usage)).is_err() {
+                    defmt::warn!("hid: too many keys held");
+                }
+                self.keyboard_report()
+            }
+            HidAction::Button(n) => {
+                self.buttons |= 1 << (n & 31);
+                self.gamepad_report()
+            }
+        }
+    }
+
+    /// Release a key or button. Returns the report to send.
+    pub fn release(&mut self, action: HidAction) -> Report {
+        match action {
+            HidAction::Key(mods, usage) => {
+                if let Some(i) = self.keys.iter().position(|k| *k == (mods, usage)) {
+                    self.keys.remove(i);
+                }
+                self.keyboard_report()
+            }
+            HidAction::Button(n) => {
+                self.buttons &= !(1 << (n & 31));
+                self.gamepad_report()
+            }
+        }
+    }
+}
+
+impl Default for HidState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+////////////////////////////////
+////////////////////////////////
+// Firmware interface
+////////////////////////////////
+////////////////////////////////
+
+static STATE: Mutex<ThreadModeRawMutex, RefCell<HidState>> =
+    Mutex::new(RefCell::new(HidState::new()));
+
+// Latest report of each kind. A report holds everything that is down, so only the latest one
+// matters, and a release can't be lost when the host is slow to read.
+static KEYBOARD: Signal<ThreadModeRawMutex, [u8; 8]> = Signal::new();
+static GAMEPAD: Signal<ThreadModeRawMutex, [u8; 4]> = Signal::new();
+
+fn send(report: Report) {
+    match report {
+        Report::Keyboard(report) => KEYBOARD.signal(report),
+        Report::Gamepad(report) => GAMEPAD.signal(report),
+    }
+}
+
+/// Press a key or button.
+pub fn press(action: HidAction) {
+    send(STATE.lock(|s| s.borrow_mut().press(action)));
+}
+
+/// Release a key or button.
+pub fn release(action: HidAction) {
+    send(STATE.lock(|s| s.borrow_mut().release(action)));
+}
+
+/// Handle sending HID reports until connection breaks
+pub async fn hid_session<'d, T: Instance + 'd>(
+    keyboard: &mut HidWriter<'d, Driver<'d, T>, 8>,
+    gamepad: &mut HidWriter<'d, Driver<'d, T>, 4>,
+) -> Result<(), Disconnected> {
+    loop {
+        match select(KEYBOARD.wait(), GAMEPAD.wait()).await {
+            Either::First(report) => keyboard.write(&report).await?,
+            Either::Second(report) => gamepad.write(&report).await?,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Rolling log of what was played recently.
+//!
+//! Everything played in the last few minutes is kept, independently of the looper, so that a
+//! practice session can be downloaded as a Standard MIDI File after the fact (see
+//! [`crate::serial`]).
+//!
+//! The log holds at most [`MAX_EVENTS`] events. When it is full, the oldest events are dropped
+//! even if they are still within the window, so dense playing (fast passages, lots of pedalling)
+//! is kept for less than the whole window.
+//!
+//! The log itself is in [`state`]; this module connects it to the rest of the firmware.
+
+use crate::smf;
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Instant;
+use portable_atomic::{AtomicBool, Ordering};
+
+pub mod state;
+
+pub use state::History;
+
+/// Maximum amount of events kept. Older events are dropped first.
+///
+/// This is about 7 events (note-ons, note-offs, pedal changes) a second over the default window;
+/// at 8 bytes an event, more would take too much of the RAM.
+pub const MAX_EVENTS: usize = 4096;
+/// Default length of the log, in minutes.
+pub const DEFAULT_WINDOW_MIN: u32 = 10;
+
+////////////////////////////////
+////////////////////////////////
+// Firmware interface
+////////////////////////////////
+////////////////////////////////
+
+static HISTORY: Mutex<ThreadModeRawMutex, RefCell<History<MAX_EVENTS>>> =
+    Mutex::new(RefCell::new(History::new(DEFAULT_WINDOW_MIN * 60_000)));
+
+/// The log is being downloaded, so it must not change.
+static EXPORTING: AtomicBool = AtomicBool::new(false);
+
+fn now_ms() -> u64 {
+    Instant::now().as_millis()
+}
+
+/// Log a channel message (status and data bytes) that was just sent to the host.
+pub fn capture(msg: [u8; 3]) {
+    if EXPORTING.load(Ordering::Relaxed) {
+        return;
+    }
+    HISTORY.lock(|h| h.borrow_mut().push(now_ms(), msg));
+}
+
+/// Change how long the log is, in minutes.
+pub fn set_window(minutes: u32) {
+    HISTORY.lock(|h| h.borrow_mut().set_window(minutes * 60_000));
+}
+
+/// Forget everything played so far.
+pub fn clear() {
+    HISTORY.lock(|h| h.borrow_mut().clear());
+}
+
+/// Start writing the log as a Standard MIDI File. See [`read_smf`].
+///
+/// Nothing is logged until [`end_export`] is called. Returns `None` if the log is empty.
+pub fn start_export() -> Option<smf::Encoder> {
+    HISTORY.lock(|h| {
+        let mut history = h.borrow_mut();
+        history.expire(now_ms());
+        let encoder = history.encoder()?;
+        EXPORTING.store(true, Ordering::Relaxed);
+        Some(encoder)
+    })
+}
+
+/// Write the next bytes of the log's Standard MIDI File.
+pub fn read_smf(encoder: &mut smf::Encoder, buf: &mut [u8]) -> usize {
+    HISTORY.lock(|h| {
+        let history = h.borrow();
+        encoder.read(|i| history.get(i), buf)
+    })
+}
+
+/// Resume logging after a download.
+pub fn end_export() {
+    EXPORTING.store(false, Ordering::Relaxed);
+}
@@ -0,0 +1,232 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Log of recent events.
+//!
+//! [`History`] works on [`Event`]s, with times in milliseconds. It doesn't depend on the
+//! hardware, so it can be tested on the host.
+
+use crate::smf::{self, Event};
+
+/// Events from the last few minutes.
+///
+/// Event times are in ms since `origin`, which is moved forward as old events are dropped, so
+/// that they never overflow.
+pub struct History<const N: usize> {
+    events: heapless::Deque<Event, N>,
+    /// Time (ms) event times are relative to.
+    origin: u64,
+    /// Length of the log, in ms.
+    window: u32,
+}
+
+impl<const N: usize> History<N> {
+    /// New log keeping events for `window` ms.
+    pub const fn new(window: u32) -> Self {
+        History {
+            events: heapless::Deque::new(),
+            origin: 0,
+            window,
+        }
+    }
+
+    /// Change how long events are kept, in ms.
+    pub fn set_window(&mut self, window: u32) {
+        self.window = window;
+    }
+
+    /// Drop events that are too old, at some time in ms.
+    pub fn expire(&mut self, now: u64) {
+        while let Some(ev) = self.events.front() {
+            if now.saturating_sub(self.origin + ev.time as u64) <= self.window as u64 {
+                break;
+            }
+            self.events.pop_front();
+        }
+    }
+
+    /// Make event times relative to the oldest event.
+    fn rebase(&mut self) {
+        let Some(first) = self.events.front().map(|ev| ev.time) else {
+            return;
+        };
+        for ev in self.events.iter_mut() {
+            ev.time -= first;
+        }
+        self.origin += first as u64;
+    }
+
+    /// Add a message, sent at some time in ms.
+    pub fn push(&mut self, now: u64, msg: [u8; 3]) {
+        self.expire(now);
+        if self.events.is_empty() {
+            self.origin = now;
+        } else if now - self.origin > u32::MAX as u64 / 2 {
+            self.rebase();
+        }
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+        let time = (now - self.origin) as u32;
+        let _ = self.events.push_back(Event { time, msg });
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Event by index, oldest first. Times are relative to an arbitrary origin.
+    pub fn get(&self, i: usize) -> Option<Event> {
+        let (a, b) = self.events.as_slices();
+        if i < a.len() {
+            Some(a[i])
+        } else {
+            b.get(i - a.len()).copied()
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// Start writing the events as a Standard MIDI File, with times relative to the oldest event.
+    /// Returns `None` if there are no events.
+    ///
+    /// The events must then be given to [`smf::Encoder::read`] with [`History::get`].
+    pub fn encoder(&self) -> Option<smf::Encoder> {
+        let origin = self.get(0)?.time;
+        Some(smf::Encoder::new(self.iter(), origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8) -> [u8; 3] {
+        [0x90, note, 100]
+    }
+
+    fn times<const N: usize>(h: &History<N>) -> heapless::Vec<u32, N> {
+        h.iter().map(|e| e.time).collect()
+    }
+
+    fn notes<const N: usize>(h: &History<N>) -> heapless::Vec<u8, N> {
+        (0..h.len()).map(|i| h.get(i).unwrap().msg[1]).collect()
+    }
+
+    #[test]
+    fn full_log_drops_oldest() {
+        let mut h = History::<4>::new(u32::MAX);
+        for i in 0..6 {
+            h.push(i * 10, note_on(60 + i as u8));
+        }
+        assert_eq!(h.len(), 4);
+        assert_eq!(notes(&h), [62, 63, 64, 65]);
+        assert_eq!(times(&h), [20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn get_across_ring_wrap() {
+        let mut h = History::<4>::new(u32::MAX);
+        for i in 0..7 {
+            h.push(i, note_on(60 + i as u8));
+        }
+        // the events are split in two slices of the ring buffer
+        assert_eq!(notes(&h), [63, 64, 65, 66]);
+        assert_eq!(h.get(4), None);
+    }
+
+    #[test]
+    fn expire_drops_events_outside_window() {
+        let mut h = History::<8>::new(1000);
+        h.push(0, note_on(60));
+        h.push(500, note_on(62));
+        h.push(1000, note_on(64));
+        h.expire(1500);
+        assert_eq!(notes(&h), [62, 64]);
+        h.expire(3000);
+        assert!(h.is_empty());
+    }
+
+    #[test]
+    fn push_expires_old_events() {
+        let mut h = History::<8>::new(1000);
+        h.push(0, note_on(60));
+        h.push(1500, note_on(62));
+        assert_eq!(notes(&h), [62]);
+    }
+
+    #[test]
+    fn empty_log_restarts_at_first_event() {
+        let mut h = History::<8>::new(1000);
+        h.push(100, note_on(60));
+        h.push(10_000_000_000, note_on(62));
+        assert_eq!(times(&h), [0]);
+    }
+
+    #[test]
+    fn times_are_rebased_before_overflowing() {
+        let mut h = History::<8>::new(3_000_000_000);
+        h.push(0, note_on(60));
+        h.push(2_000_000_000, note_on(62));
+        h.push(4_000_000_000, note_on(64));
+        // this wouldn't fit in a u32 from the first event
+        h.push(5_500_000_000, note_on(65));
+        assert_eq!(notes(&h), [64, 65]);
+        assert_eq!(times(&h), [0, 1_500_000_000]);
+    }
+
+    #[test]
+    fn export_starts_at_oldest_event() {
+        let mut h = History::<8>::new(1000);
+        h.push(5000, note_on(60));
+        h.push(5800, note_on(62));
+        h.push(6500, note_on(64));
+        let mut enc = h.encoder().unwrap();
+        let mut file = heapless::Vec::<u8, 64>::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let n = enc.read(|i| h.get(i), &mut buf);
+            if n == 0 {
+                break;
+            }
+            file.extend_from_slice(&buf[..n]).unwrap();
+        }
+        assert_eq!(file.len(), enc.file_len());
+        // header, division, track header, tempo
+        let track = &file[22 + 7..];
+        assert_eq!(&track[..4], [0x00, 0x90, 62, 100]);
+        // 700 ms later
+        assert_eq!(&track[4..9], [0x85, 0x3c, 0x90, 64, 100]);
+    }
+
+    #[test]
+    fn empty_log_has_nothing_to_export() {
+        let h = History::<8>::new(1000);
+        assert!(h.encoder().is_none());
+    }
+}
@@ -8,9 +8,19 @@ use embassy_time::Timer;
 use {defmt_rtt as _, panic_probe as _};
 
 pub mod blinky;
+pub mod bootsel;
+pub mod clock;
+pub mod fnkey;
+pub mod hid;
+pub mod history;
+pub mod looper;
 pub mod matrix;
 pub mod midi;
+pub mod perform;
 pub mod pins;
+pub mod serial;
+pub mod smf;
+pub mod storage;
 pub mod usb;
 
 /// Wrapper over unwrap.
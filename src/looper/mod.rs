@@ -0,0 +1,247 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Recorder and looper.
+//!
+//! Everything sent over MIDI (notes, controllers, etc.) can be recorded, then played back in a
+//! loop. While the loop plays, more can be recorded over it (overdub). The state machine is in
+//! [`state`]; this module connects it to the rest of the firmware.
+
+use crate::midi;
+use crate::smf::{self, Event};
+use crate::storage;
+use core::cell::RefCell;
+use defmt::Format;
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer};
+use portable_atomic::{AtomicBool, Ordering};
+
+pub mod state;
+
+pub use state::{Looper, Output, State};
+
+/// Maximum amount of events in a recording.
+pub const MAX_EVENTS: usize = 1024;
+
+/// Looper controls.
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum Command {
+    /// Start a new recording.
+    Record,
+    /// Play the recording in a loop (ends the recording, if there is one).
+    Play,
+    /// Start or stop recording over the loop.
+    Overdub,
+    /// Stop recording or playing.
+    Stop,
+    /// Save the recording to flash.
+    Save,
+    /// Load the recording saved in flash.
+    Load,
+}
+
+////////////////////////////////
+////////////////////////////////
+// Firmware interface
+////////////////////////////////
+////////////////////////////////
+
+static LOOPER: Mutex<ThreadModeRawMutex, RefCell<Looper<MAX_EVENTS>>> =
+    Mutex::new(RefCell::new(Looper::new()));
+
+/// Wakes the looper task when playback starts or stops.
+static WAKE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// The recording is being downloaded, so it must not change.
+static EXPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Identifies a recording saved in flash.
+const MAGIC: &[u8; 4] = b"GLP1";
+/// Size of the header (magic, event count, loop length) of a saved recording.
+const HEADER_LEN: usize = 12;
+/// Size of a saved event.
+const EVENT_LEN: usize = 8;
+
+fn now_ms() -> u64 {
+    Instant::now().as_millis()
+}
+
+/// Run something on the looper, then send the messages it outputs.
+async fn with_looper<R>(f: impl FnOnce(&mut Looper<MAX_EVENTS>, &mut Output) -> R) -> R {
+    let mut out = Output::new();
+    let ret = LOOPER.lock(|l| f(&mut l.borrow_mut(), &mut out));
+    if out.is_full() {
+        defmt::warn!("looper: output full, some messages were dropped");
+    }
+    for msg in out {
+        midi::raw(msg).await;
+    }
+    ret
+}
+
+/// Record a channel message (status and data bytes) that was just sent to the host.
+pub fn capture(msg: [u8; 3]) {
+    if !LOOPER.lock(|l| l.borrow_mut().capture(now_ms(), msg)) {
+        defmt::warn!("looper: recording full");
+    }
+}
+
+/// Looper state.
+pub fn state() -> State {
+    LOOPER.lock(|l| l.borrow().state())
+}
+
+/// Run a looper control.
+pub async fn command(cmd: Command) {
+    if EXPORTING.load(Ordering::Relaxed)
+        && matches!(cmd, Command::Record | Command::Overdub | Command::Load)
+    {
+        defmt::warn!("looper: can not {} while the recording is downloading", cmd);
+        return;
+    }
+    defmt::info!("looper: {}", cmd);
+    let now = now_ms();
+    match cmd {
+        Command::Record => with_looper(|l, out| l.record(now, out)).await,
+        Command::Play => with_looper(|l, out| l.play(now, out)).await,
+        Command::Overdub => with_looper(|l, out| l.overdub(now, out)).await,
+        Command::Stop => with_looper(|l, out| l.stop(now, out)).await,
+        Command::Save => {
+            if let Err(e) = save() {
+                defmt::error!("looper: could not save: {}", e);
+            }
+        }
+        Command::Load => {
+            if let Err(e) = load().await {
+                defmt::error!("looper: could not load: {}", e);
+            }
+        }
+    }
+    WAKE.signal(());
+}
+
+/// Save the recording to flash.
+///
+/// The recording is copied out first, so that the looper isn't locked while the flash is erased
+/// and written, which takes a while.
+fn save() -> Result<(), storage::Error> {
+    let region = &storage::RECORDING;
+    let (events, length) = LOOPER.lock(|l| {
+        let looper = l.borrow();
+        let events: heapless::Vec<Event, MAX_EVENTS> = looper.events().iter().copied().collect();
+        (events, looper.length())
+    });
+    let count = events.len().min((region.size() - HEADER_LEN) / EVENT_LEN);
+
+    storage::erase(region)?;
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..8].copy_from_slice(&(count as u32).to_le_bytes());
+    header[8..].copy_from_slice(&length.to_le_bytes());
+    storage::write(region, 0, &header)?;
+
+    // write a few events at a time
+    let mut buf = [0u8; 32 * EVENT_LEN];
+    for (i, chunk) in events[..count].chunks(buf.len() / EVENT_LEN).enumerate() {
+        for (ev, bytes) in chunk.iter().zip(buf.chunks_exact_mut(EVENT_LEN)) {
+            bytes[..4].copy_from_slice(&ev.time.to_le_bytes());
+            bytes[4..7].copy_from_slice(&ev.msg);
+            bytes[7] = 0;
+        }
+        let offset = HEADER_LEN + i * buf.len();
+        storage::write(region, offset as u32, &buf[..chunk.len() * EVENT_LEN])?;
+    }
+    defmt::info!("looper: saved {} events", count);
+    Ok(())
+}
+
+/// Load the recording from flash.
+async fn load() -> Result<(), storage::Error> {
+    let region = &storage::RECORDING;
+    let mut header = [0u8; HEADER_LEN];
+    storage::read(region, 0, &mut header)?;
+    if &header[..4] != MAGIC {
+        defmt::warn!("looper: no recording saved");
+        return Ok(());
+    }
+    let count = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let length = u32::from_le_bytes(header[8..].try_into().unwrap());
+
+    let read_event = |i: usize| -> Result<Event, storage::Error> {
+        let mut bytes = [0u8; EVENT_LEN];
+        storage::read(region, (HEADER_LEN + i * EVENT_LEN) as u32, &mut bytes)?;
+        Ok(Event {
+            time: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            msg: [bytes[4], bytes[5], bytes[6]],
+        })
+    };
+    let mut res = Ok(());
+    with_looper(|l, out| {
+        let events = (0..count).map_while(|i| read_event(i).map_err(|e| res = Err(e)).ok());
+        l.set_recording(events, length, out);
+        defmt::info!("looper: loaded {} events", l.events().len());
+    })
+    .await;
+    res
+}
+
+/// Start writing the recording as a Standard MIDI File. See [`read_smf`].
+///
+/// Commands that change the recording are refused until [`end_export`] is called. Returns `None`
+/// while recording, since the events are still changing.
+pub fn start_export() -> Option<smf::Encoder> {
+    LOOPER.lock(|l| {
+        let looper = l.borrow();
+        match looper.state() {
+            State::Recording | State::Overdub => None,
+            _ => {
+                EXPORTING.store(true, Ordering::Relaxed);
+                Some(smf::Encoder::new(looper.events().iter(), 0))
+            }
+        }
+    })
+}
+
+/// Write the next bytes of the recording's Standard MIDI File.
+pub fn read_smf(encoder: &mut smf::Encoder, buf: &mut [u8]) -> usize {
+    LOOPER.lock(|l| {
+        let looper = l.borrow();
+        encoder.read(|i| looper.events().get(i).copied(), buf)
+    })
+}
+
+/// Allow changing the recording again after a download.
+pub fn end_export() {
+    EXPORTING.store(false, Ordering::Relaxed);
+}
+
+/// Task playing back the loop.
+#[embassy_executor::task]
+pub async fn looper_task() {
+    loop {
+        let next = with_looper(|l, out| l.poll(now_ms(), out)).await;
+        match next {
+            Some(ms) => {
+                select(Timer::at(Instant::from_millis(ms)), WAKE.wait()).await;
+            }
+            None => WAKE.wait().await,
+        }
+    }
+}
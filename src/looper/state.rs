@@ -0,0 +1,385 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Looper state machine.
+//!
+//! [`Looper`] works on [`Event`]s, with times in milliseconds. It doesn't depend on the
+//! hardware, so it can be tested on the host.
+
+use crate::smf::Event;
+use defmt::Format;
+
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum State {
+    /// Not recording or playing.
+    Idle,
+    /// Recording the first pass, which sets the length of the loop.
+    Recording,
+    /// Playing the loop.
+    Playing,
+    /// Playing the loop, and recording over it.
+    Overdub,
+}
+
+/// Messages (status and data bytes) produced by the looper, to be sent.
+pub type Output = heapless::Vec<[u8; 3], 128>;
+
+/// Push a message, dropping it if the output is full.
+fn push(out: &mut Output, msg: [u8; 3]) {
+    let _ = out.push(msg);
+}
+
+/// Looper state machine.
+pub struct Looper<const N: usize> {
+    events: heapless::Vec<Event, N>,
+    state: State,
+    /// Length of the loop in ms.
+    length: u32,
+    /// Time (ms) the current recording or pass over the loop started.
+    start: u64,
+    /// Next event to play.
+    next: usize,
+    /// Notes sounding from playback, as bitmasks by channel.
+    sounding: [u128; 16],
+}
+
+impl<const N: usize> Looper<N> {
+    pub const fn new() -> Self {
+        Looper {
+            events: heapless::Vec::new(),
+            state: State::Idle,
+            length: 0,
+            start: 0,
+            next: 0,
+            sounding: [0; 16],
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Recorded events, ordered by time since the start of the loop.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Length of the loop in ms.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    /// Replace the recording (e.g. when loading it from flash).
+    pub fn set_recording(
+        &mut self,
+        events: impl Iterator<Item = Event>,
+        length: u32,
+        out: &mut Output,
+    ) {
+        self.silence(out);
+        self.state = State::Idle;
+        self.events.clear();
+        for ev in events.take(N) {
+            let _ = self.events.push(ev);
+        }
+        self.length = length;
+    }
+
+    /// Start a new recording.
+    pub fn record(&mut self, now: u64, out: &mut Output) {
+        self.silence(out);
+        self.events.clear();
+        self.length = 0;
+        self.start = now;
+        self.state = State::Recording;
+    }
+
+    /// End the first recording pass, which sets the loop length.
+    fn finish_recording(&mut self, now: u64) {
+        if self.state == State::Recording {
+            self.length = (now - self.start).clamp(1, u32::MAX as u64) as u32;
+        }
+    }
+
+    /// Play the loop from the start.
+    pub fn play(&mut self, now: u64, out: &mut Output) {
+        self.finish_recording(now);
+        self.silence(out);
+        if self.length == 0 {
+            self.state = State::Idle;
+            return;
+        }
+        self.state = State::Playing;
+        self.start = now;
+        self.next = 0;
+    }
+
+    /// Start or stop overdubbing. Ends the first recording pass if there is one.
+    pub fn overdub(&mut self, now: u64, out: &mut Output) {
+        match self.state {
+            State::Idle => {}
+            State::Recording => {
+                self.play(now, out);
+                self.state = State::Overdub;
+            }
+            State::Playing => self.state = State::Overdub,
+            State::Overdub => self.state = State::Playing,
+        }
+    }
+
+    /// Stop recording or playing.
+    pub fn stop(&mut self, now: u64, out: &mut Output) {
+        self.finish_recording(now);
+        self.silence(out);
+        self.state = State::Idle;
+    }
+
+    /// Record a message that was just sent.
+    ///
+    /// Returns false if the recording is full, so the message was dropped.
+    pub fn capture(&mut self, now: u64, msg: [u8; 3]) -> bool {
+        let res = match self.state {
+            State::Idle | State::Playing => return true,
+            State::Recording => {
+                let time = (now - self.start).min(u32::MAX as u64) as u32;
+                self.events.push(Event { time, msg })
+            }
+            State::Overdub => {
+                let time = ((now - self.start) % self.length as u64) as u32;
+                let mut ev = Event { time, msg };
+                let mut pos = self.events.partition_point(|e| e.time <= time);
+                if pos > self.next {
+                    // playback is running late; keep the new event in the part that was
+                    // already played so it doesn't get played right back
+                    pos = self.next;
+                    ev.time = ev.time.min(self.events[pos].time);
+                }
+                let res = self.events.insert(pos, ev);
+                if res.is_ok() {
+                    self.next += 1;
+                }
+                res
+            }
+        };
+        res.is_ok()
+    }
+
+    /// Release every note sounding from playback.
+    fn silence(&mut self, out: &mut Output) {
+        for (channel, notes) in self.sounding.iter_mut().enumerate() {
+            for note in 0..128 {
+                if *notes & (1 << note) != 0 {
+                    push(out, [0x80 | channel as u8, note, 0]);
+                }
+            }
+            *notes = 0;
+        }
+    }
+
+    /// Play the events that are due.
+    ///
+    /// Returns the time (ms) at which this should be called again, or `None` if not playing.
+    pub fn poll(&mut self, now: u64, out: &mut Output) -> Option<u64> {
+        if !matches!(self.state, State::Playing | State::Overdub) {
+            return None;
+        }
+        loop {
+            let elapsed = now.saturating_sub(self.start);
+            if elapsed >= self.length as u64 {
+                // back to the start of the loop
+                self.silence(out);
+                self.start += (elapsed / self.length as u64) * self.length as u64;
+                self.next = 0;
+                continue;
+            }
+            match self.events.get(self.next) {
+                Some(ev) if ev.time as u64 <= elapsed => {
+                    let msg = ev.msg;
+                    let bit = 1u128 << (msg[1] & 0x7f);
+                    let channel = (msg[0] & 0xf) as usize;
+                    match msg[0] & 0xf0 {
+                        0x90 if msg[2] > 0 => self.sounding[channel] |= bit,
+                        0x80 | 0x90 => self.sounding[channel] &= !bit,
+                        _ => {}
+                    }
+                    push(out, msg);
+                    self.next += 1;
+                }
+                Some(ev) => return Some(self.start + ev.time as u64),
+                None => return Some(self.start + self.length as u64),
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for Looper<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8) -> [u8; 3] {
+        [0x90, note, 100]
+    }
+
+    fn note_off(note: u8) -> [u8; 3] {
+        [0x80, note, 0]
+    }
+
+    /// Loop of 1000 ms with notes 60 at 0 ms and 62 at 500 ms.
+    fn looper() -> Looper<16> {
+        let mut l = Looper::new();
+        let mut out = Output::new();
+        l.record(0, &mut out);
+        l.capture(0, note_on(60));
+        l.capture(100, note_off(60));
+        l.capture(500, note_on(62));
+        l.capture(600, note_off(62));
+        l.play(1000, &mut out);
+        assert!(out.is_empty());
+        l
+    }
+
+    fn times(l: &Looper<16>) -> heapless::Vec<u32, 16> {
+        l.events().iter().map(|e| e.time).collect()
+    }
+
+    #[test]
+    fn recording_sets_length() {
+        let l = looper();
+        assert_eq!(l.state(), State::Playing);
+        assert_eq!(l.length(), 1000);
+        assert_eq!(times(&l), [0, 100, 500, 600]);
+    }
+
+    #[test]
+    fn poll_plays_due_events() {
+        let mut l = looper();
+        let mut out = Output::new();
+        assert_eq!(l.poll(1000, &mut out), Some(1100));
+        assert_eq!(out, [note_on(60)]);
+        out.clear();
+        assert_eq!(l.poll(1050, &mut out), Some(1100));
+        assert!(out.is_empty());
+        assert_eq!(l.poll(1550, &mut out), Some(1600));
+        assert_eq!(out, [note_off(60), note_on(62)]);
+    }
+
+    #[test]
+    fn poll_wraps_at_end_of_loop() {
+        let mut l = looper();
+        let mut out = Output::new();
+        l.poll(1000, &mut out);
+        out.clear();
+        assert_eq!(l.poll(1700, &mut out), Some(2000));
+        assert_eq!(out, [note_off(60), note_on(62), note_off(62)]);
+        out.clear();
+        // back to the start of the loop
+        assert_eq!(l.poll(2000, &mut out), Some(2100));
+        assert_eq!(out, [note_on(60)]);
+    }
+
+    #[test]
+    fn poll_releases_notes_when_wrapping() {
+        let mut l = Looper::<16>::new();
+        let mut out = Output::new();
+        l.record(0, &mut out);
+        // held past the end of the loop
+        l.capture(200, note_on(64));
+        l.play(400, &mut out);
+        l.poll(600, &mut out);
+        assert_eq!(out, [note_on(64)]);
+        out.clear();
+        assert_eq!(l.poll(800, &mut out), Some(1000));
+        assert_eq!(out, [note_off(64)]);
+    }
+
+    #[test]
+    fn poll_skips_whole_loops() {
+        let mut l = looper();
+        let mut out = Output::new();
+        // nothing polled for several loops
+        assert_eq!(l.poll(5050, &mut out), Some(5100));
+        assert_eq!(out, [note_on(60)]);
+    }
+
+    #[test]
+    fn overdub_inserts_sorted() {
+        let mut l = looper();
+        let mut out = Output::new();
+        l.overdub(1000, &mut out);
+        assert_eq!(l.state(), State::Overdub);
+        l.poll(1000, &mut out);
+        l.poll(1300, &mut out);
+        l.capture(1300, note_on(67));
+        assert_eq!(times(&l), [0, 100, 300, 500, 600]);
+        assert_eq!(l.events()[2].msg, note_on(67));
+        // the new event was already played
+        out.clear();
+        l.poll(1500, &mut out);
+        assert_eq!(out, [note_on(62)]);
+
+        // next pass, recorded at the very end
+        l.poll(1999, &mut out);
+        l.capture(1999, note_off(67));
+        assert_eq!(times(&l), [0, 100, 300, 500, 600, 999]);
+        out.clear();
+        l.poll(2000, &mut out);
+        assert_eq!(out, [note_on(60)]);
+    }
+
+    #[test]
+    fn overdub_while_late_keeps_unplayed_events_after() {
+        let mut l = looper();
+        let mut out = Output::new();
+        l.overdub(1000, &mut out);
+        l.poll(1000, &mut out);
+        out.clear();
+        // captured before the events that were due got played
+        l.capture(1550, note_on(67));
+        assert_eq!(times(&l), [0, 100, 100, 500, 600]);
+        l.poll(1550, &mut out);
+        assert_eq!(out, [note_off(60), note_on(62)]);
+    }
+
+    #[test]
+    fn capture_reports_full_recording() {
+        let mut l = Looper::<2>::new();
+        let mut out = Output::new();
+        l.record(0, &mut out);
+        assert!(l.capture(0, note_on(60)));
+        assert!(l.capture(10, note_off(60)));
+        assert!(!l.capture(20, note_on(62)));
+        assert_eq!(l.events().len(), 2);
+    }
+
+    #[test]
+    fn stop_releases_notes() {
+        let mut l = looper();
+        let mut out = Output::new();
+        l.poll(1000, &mut out);
+        out.clear();
+        l.stop(1050, &mut out);
+        assert_eq!(out, [note_off(60)]);
+        assert_eq!(l.poll(1100, &mut out), None);
+    }
+}
@@ -1,12 +1,39 @@
 //! Key matrix scanner + other interfacing utilities
 
+use crate::blinky;
+use crate::bootsel;
+use crate::clock;
+use crate::fnkey::{FnAction, FnEvent, FnMode};
+use crate::hid;
+use crate::looper;
 use crate::midi;
+use crate::perform;
 use crate::pins;
 use crate::unwrap;
+use crate::usb;
 use core::cmp::{max, min};
 use embassy_rp::gpio;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
 use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, Ordering};
 
+/// Number of possible MIDI notes.
+const MAX_NOTES: usize = 128;
+/// Time N2 must stay open for a key to be re-armed, so that bouncing isn't a re-strike.
+const REARM_DEBOUNCE_US: u64 = 1000;
+/// Time between scans while the host is asleep, to save power and I2C traffic.
+const SUSPENDED_SCAN_INTERVAL_MS: u64 = 20;
+/// Time without any key down before idling, if the pin driver has an interrupt pin.
+const IDLE_AFTER_MS: u64 = 1000;
+/// Longest wait while idle, so that function-key and other actions are still handled.
+const IDLE_WAIT_MS: u64 = 20;
+/// Number of idle waits between real scans, in case an interrupt was missed (e.g. the INT line is
+/// unwired, or an extender was reset and lost its interrupt setup).
+const IDLE_SCAN_EVERY: u32 = 10;
+/// Time between checks that the extenders weren't reset (see `TransparentPins::check_extenders`).
+const EXTENDER_CHECK_INTERVAL_MS: u64 = 1000;
+
+#[derive(Clone, Copy)]
 pub enum NormalState {
     /// Normal open
     NO,
@@ -16,12 +43,24 @@ pub enum NormalState {
 
 /// Profile to map from key press duration to MIDI velocity.
 /// https://www.desmos.com/calculator/mynk7thhzp
+#[derive(Clone, Copy)]
 pub enum VelocityProfile {
     Linear,
     Heavy,
     Light,
 }
 
+impl VelocityProfile {
+    /// Velocity for a key press that took `us` microseconds.
+    fn velocity(&self, us: u64) -> u8 {
+        match self {
+            VelocityProfile::Heavy => velocity_heavy(us),
+            VelocityProfile::Linear => velocity_linear(us),
+            VelocityProfile::Light => velocity_light(us),
+        }
+    }
+}
+
 fn velocity_light(us: u64) -> u8 {
     if us <= 60000 {
         min(127, (135000 - us * 6 / 5) / 1000) as u8
@@ -42,21 +81,535 @@ fn velocity_linear(us: u64) -> u8 {
     (max(120900 - (us as i32), 5000) / 1000) as u8
 }
 
-pub struct Config {
+/// How hard a key was struck.
+#[derive(Clone, Copy)]
+enum Strike {
+    /// Microseconds between the N1 and N2 switches closing.
+    Timed(u64),
+    /// Switch without velocity detection.
+    Fixed(u8),
+}
+
+/// Maximum amount of zones in a [`Config`].
+pub const MAX_ZONES: usize = 4;
+
+/// Range of keys that sends on its own MIDI channel.
+///
+/// Zones may overlap. A key that is in many zones (layering) sends a note in each of them.
+#[derive(Clone, Copy)]
+pub struct Zone {
+    /// Lowest key in the zone (inclusive).
+    pub low: midi::Note,
+    /// Highest key in the zone (inclusive).
+    pub high: midi::Note,
+    /// MIDI channel (0-15).
+    pub channel: u8,
+    /// Semitones added to every note played in this zone.
+    pub transpose: i8,
     pub velocity_prof: VelocityProfile,
+    /// Percentage the velocity is scaled by after applying the profile (100 is unchanged).
+    pub velocity_scale: u8,
+    /// Notes go through the performance modes (latch, chord, arpeggiator).
+    pub perform: bool,
+}
+
+impl Zone {
+    /// Zone covering the entire keyboard, without transposition or scaling.
+    pub const fn full(channel: u8, velocity_prof: VelocityProfile) -> Self {
+        Zone {
+            low: midi::Note::A0,
+            high: midi::Note::B8,
+            channel,
+            transpose: 0,
+            velocity_prof,
+            velocity_scale: 100,
+            perform: true,
+        }
+    }
+
+    fn contains(&self, note: midi::Note) -> bool {
+        (self.low as u8..=self.high as u8).contains(&(note as u8))
+    }
+
+    /// Note sounded by a key in this zone, with some extra transposition.
+    fn note(&self, note: midi::Note, transpose: i8) -> Option<midi::Note> {
+        note.transpose(self.transpose.saturating_add(transpose))
+    }
+
+    fn velocity(&self, strike: Strike) -> u8 {
+        let base = match strike {
+            Strike::Timed(us) => self.velocity_prof.velocity(us),
+            Strike::Fixed(velocity) => velocity,
+        };
+        (base as u32 * self.velocity_scale as u32 / 100).clamp(1, 127) as u8
+    }
+}
+
+/// Source of emulated aftertouch.
+#[derive(Clone, Copy)]
+pub enum AftertouchSource {
+    /// Pressure rises the longer a key is held down at N2. It starts rising after `delay_ms`, and
+    /// reaches the maximum `ramp_ms` later.
+    Hold { delay_ms: u32, ramp_ms: u32 },
+    /// Pressure rises by `step` every time N2 is struck again without releasing N1.
+    Restrike { step: u8 },
+}
+
+/// Emulated aftertouch, for keyboards without pressure sensors.
+#[derive(Clone, Copy)]
+pub struct Aftertouch {
+    /// Send Polyphonic Key Pressure per note, instead of Channel Pressure.
+    pub poly: bool,
+    pub source: AftertouchSource,
+}
+
+impl Aftertouch {
+    /// Pressure for a key held at N2 for `held_us` microseconds.
+    fn hold_pressure(&self, held_us: u64, current: u8) -> u8 {
+        match self.source {
+            AftertouchSource::Hold { delay_ms, ramp_ms } => {
+                let delay_us = delay_ms as u64 * 1000;
+                let ramp_us = max(ramp_ms as u64 * 1000, 1);
+                let since = held_us.saturating_sub(delay_us);
+                min(127, since * 127 / ramp_us) as u8
+            }
+            AftertouchSource::Restrike { .. } => current,
+        }
+    }
+
+    /// Pressure after a key is struck again at N2.
+    fn restrike_pressure(&self, current: u8) -> u8 {
+        match self.source {
+            AftertouchSource::Hold { .. } => current,
+            AftertouchSource::Restrike { step } => min(127, current.saturating_add(step)),
+        }
+    }
+}
+
+pub struct Config {
+    /// Zones that keys are sent to. Keys outside all zones are ignored.
+    pub zones: heapless::Vec<Zone, MAX_ZONES>,
+    /// Semitones added to every zone. Can be changed in function-key mode.
+    pub transpose: i8,
+    /// Current program. Can be changed in function-key mode.
+    pub program: u8,
+    /// Current bank. Can be changed in function-key mode.
+    pub bank: u16,
+    /// Function-key mode, if enabled.
+    pub fn_mode: Option<FnMode>,
+    /// Pedal or button (active low) that opens the function-key menu while held.
+    pub fn_pin: Option<gpio::AnyPin>,
+    /// Lists of settings applied by [`midi::KeyAction::Macro`] keys, indexed by macro ID.
+    pub macros: &'static [&'static [FnAction]],
+    /// Emulated aftertouch, if enabled.
+    pub aftertouch: Option<Aftertouch>,
+    /// Keys that send keystrokes or gamepad buttons in HID mode.
+    pub hid: Option<hid::HidMap>,
+    /// HID mode is on. Can be changed in function-key mode.
+    pub hid_mode: bool,
+    /// Reboot into BOOTSEL mode if the function-key gesture (chord or pin) is held while
+    /// powering up.
+    pub bootsel_gesture: bool,
+    /// Wake the host up from USB suspend when a key is pressed. The host must allow it.
+    pub wakeup: bool,
+}
+
+/// Where a key's note was sent.
+#[derive(Clone, Copy)]
+enum Route {
+    /// MIDI note on a channel, directly or through the performance modes.
+    Midi {
+        channel: u8,
+        note: midi::Note,
+        perform: bool,
+    },
+    /// Keystroke or gamepad button in HID mode.
+    Hid(hid::HidAction),
+}
+
+impl Route {
+    /// Send the Note-Off (or key release) matching what was sent.
+    async fn release(self) {
+        match self {
+            Route::Midi {
+                channel,
+                note,
+                perform: true,
+            } => perform::note_off(channel, note).await,
+            Route::Midi {
+                channel,
+                note,
+                perform: false,
+            } => midi::MidiChannel::new(channel).note_off(note, 0).await,
+            Route::Hid(action) => hid::release(action),
+        }
+    }
+}
+
+/// Every place a key's note was sent (one per zone it is in).
+type Routes = [Option<Route>; MAX_ZONES];
+
+/// Scan state of a single note.
+#[derive(Clone, Copy)]
+struct NoteState {
+    /// (for velocity detection) moment key is first touched
+    first: Option<Instant>,
+    /// (for debouncing) moment note was last on
+    on: Option<Instant>,
+    /// Where the note was sent when the key went down, so that it is released the same way even
+    /// if the transposition, channel or HID mode changed since.
+    routes: Routes,
+    /// Note was actually sent (not used by function-key mode).
+    sounding: bool,
+    /// Moment the N2 switch closed, while it stays closed.
+    bottom: Option<Instant>,
+    /// Emulated aftertouch pressure last sent.
+    pressure: u8,
+    /// Moment N2 opened while the note was still on.
+    rearm: Option<Instant>,
 }
 
+impl NoteState {
+    const fn new() -> Self {
+        NoteState {
+            first: None,
+            on: None,
+            routes: [None; MAX_ZONES],
+            sounding: false,
+            bottom: None,
+            pressure: 0,
+            rearm: None,
+        }
+    }
+
+    /// Note was sent on a MIDI channel.
+    fn sends_on(&self, channel: u8) -> bool {
+        self.routes
+            .iter()
+            .flatten()
+            .any(|r| matches!(r, Route::Midi { channel: c, .. } if *c == channel))
+    }
+
+    /// Release the note wherever it was sent.
+    async fn stop(&mut self) {
+        for route in self.routes.iter_mut() {
+            if let Some(route) = route.take() {
+                route.release().await;
+            }
+        }
+    }
+}
+
+impl Config {
+    /// HID action for a key, if HID mode is on.
+    fn hid_action(&self, note: midi::Note) -> Option<hid::HidAction> {
+        self.hid.filter(|_| self.hid_mode).and_then(|map| map(note))
+    }
+
+    /// Send a Note-On in every zone containing this key. Returns where it was sent.
+    async fn note_on(&self, note: midi::Note, strike: Strike) -> Routes {
+        let mut routes = [None; MAX_ZONES];
+        if let Some(action) = self.hid_action(note) {
+            hid::press(action);
+            routes[0] = Some(Route::Hid(action));
+            return routes;
+        }
+        let zones = self.zones.iter().filter(|z| z.contains(note));
+        for (zone, route) in zones.zip(routes.iter_mut()) {
+            if let Some(zone_note) = zone.note(note, self.transpose) {
+                let velocity = zone.velocity(strike);
+                if zone.perform {
+                    perform::note_on(zone.channel, zone_note, velocity).await;
+                } else {
+                    midi::MidiChannel::new(zone.channel)
+                        .note_on(zone_note, velocity)
+                        .await;
+                }
+                *route = Some(Route::Midi {
+                    channel: zone.channel,
+                    note: zone_note,
+                    perform: zone.perform,
+                });
+            }
+        }
+        routes
+    }
+
+    /// Send a Control Change on the channel of every zone.
+    async fn control_change(&self, cc: u8, value: u8) {
+        for zone in self.zones.iter() {
+            midi::MidiChannel::new(zone.channel)
+                .on_cable(midi::Cable::Controls)
+                .control_change(cc & 0x7f, value & 0x7f)
+                .await;
+        }
+    }
+
+    /// Handle a key press from the matrix.
+    async fn press(&mut self, note: midi::Note, strike: Strike, notes: &mut [NoteState]) {
+        let ev = match &mut self.fn_mode {
+            Some(fn_mode) => fn_mode.key(note, true),
+            None => FnEvent::Play,
+        };
+        if self.fn_event(ev, notes).await {
+            let routes = self.note_on(note, strike).await;
+            let state = &mut notes[note as usize];
+            state.routes = routes;
+            state.sounding = true;
+        }
+    }
+
+    /// Handle a key release from the matrix.
+    async fn release(&mut self, note: midi::Note, notes: &mut [NoteState]) {
+        let ev = match &mut self.fn_mode {
+            Some(fn_mode) => fn_mode.key(note, false),
+            None => FnEvent::Play,
+        };
+        if self.fn_event(ev, notes).await {
+            // channel pressure would otherwise stay on after the note stops
+            self.reset_aftertouch(note, notes).await;
+            notes[note as usize].stop().await;
+        }
+        let state = &mut notes[note as usize];
+        state.sounding = false;
+        state.bottom = None;
+        state.pressure = 0;
+        state.rearm = None;
+    }
+
+    /// Play a note again after it was re-struck without being fully released.
+    async fn retrigger(&self, note: midi::Note, strike: Strike, notes: &mut [NoteState]) {
+        if !notes[note as usize].sounding {
+            return;
+        }
+        self.reset_aftertouch(note, notes).await;
+        let state = &mut notes[note as usize];
+        state.stop().await;
+        state.routes = self.note_on(note, strike).await;
+    }
+
+    /// Bring the emulated aftertouch of a note back to 0, e.g. because it stops.
+    ///
+    /// Without polyphonic aftertouch, the channel pressure goes to the highest pressure of the
+    /// other notes still sounding on the channel instead, and to 0 once there are none left.
+    async fn reset_aftertouch(&self, note: midi::Note, notes: &mut [NoteState]) {
+        let state = notes[note as usize];
+        let (Some(aftertouch), true) = (self.aftertouch, state.pressure > 0) else {
+            return;
+        };
+        notes[note as usize].pressure = 0;
+        for route in state.routes.iter().flatten() {
+            let Route::Midi {
+                channel,
+                note: sent,
+                ..
+            } = *route
+            else {
+                continue;
+            };
+            let chan = midi::MidiChannel::new(channel);
+            if aftertouch.poly {
+                chan.key_pressure(sent, 0).await;
+            } else {
+                let pressure = notes
+                    .iter()
+                    .filter(|n| n.sounding && n.sends_on(channel))
+                    .map(|n| n.pressure)
+                    .max()
+                    .unwrap_or(0);
+                chan.channel_pressure(pressure).await;
+            }
+        }
+    }
+
+    /// Send emulated aftertouch for a note, wherever it was sent.
+    async fn send_aftertouch(&self, state: &NoteState, pressure: u8) {
+        let Some(aftertouch) = self.aftertouch else {
+            return;
+        };
+        for route in state.routes.iter().flatten() {
+            let Route::Midi { channel, note, .. } = *route else {
+                continue;
+            };
+            let chan = midi::MidiChannel::new(channel);
+            if aftertouch.poly {
+                chan.key_pressure(note, pressure).await;
+            } else {
+                chan.channel_pressure(pressure).await;
+            }
+        }
+    }
+
+    /// Update the aftertouch of a key that is held at N2.
+    ///
+    /// `restrike` is true if N2 just closed again without N1 opening.
+    async fn held(&self, state: &mut NoteState, restrike: bool) {
+        let (Some(aftertouch), true) = (self.aftertouch, state.sounding) else {
+            return;
+        };
+        let pressure = if restrike {
+            aftertouch.restrike_pressure(state.pressure)
+        } else {
+            let held_us = state.bottom.map_or(0, |t| t.elapsed().as_micros());
+            aftertouch.hold_pressure(held_us, state.pressure)
+        };
+        if pressure != state.pressure {
+            state.pressure = pressure;
+            self.send_aftertouch(state, pressure).await;
+        }
+    }
+
+    /// Act on the result of function-key mode. Returns true if the key should be played.
+    async fn fn_event(&mut self, ev: FnEvent, notes: &mut [NoteState]) -> bool {
+        match ev {
+            FnEvent::Play => return true,
+            FnEvent::Consumed => {}
+            FnEvent::Enter => {
+                defmt::info!("entering function-key mode");
+                for state in notes.iter_mut().filter(|s| s.sounding) {
+                    state.stop().await;
+                    state.sounding = false;
+                }
+                blinky::set_status(blinky::Status::Solid);
+            }
+            FnEvent::Exit => {
+                defmt::info!("leaving function-key mode");
+                blinky::set_status(blinky::Status::Heartbeat);
+            }
+            FnEvent::Action(action) => {
+                self.apply(action).await;
+                blinky::set_status(blinky::Status::Confirm(2));
+            }
+            FnEvent::Unmapped => {}
+        }
+        false
+    }
+
+    /// Apply a setting from function-key mode.
+    async fn apply(&mut self, action: FnAction) {
+        match action {
+            FnAction::VelocityProfile(prof) => {
+                for zone in self.zones.iter_mut() {
+                    zone.velocity_prof = prof;
+                }
+            }
+            FnAction::Transpose(semitones) => {
+                defmt::info!("transpose set to {}", semitones);
+                self.transpose = semitones;
+            }
+            FnAction::TransposeStep(step) => {
+                self.transpose = self.transpose.saturating_add(step);
+                defmt::info!("transpose set to {}", self.transpose);
+            }
+            FnAction::Channel(channel) => {
+                defmt::info!("channel set to {}", channel);
+                if let Some(zone) = self.zones.first_mut() {
+                    zone.channel = channel;
+                }
+            }
+            FnAction::ProgramStep(step) => {
+                self.program = self.program.saturating_add_signed(step).min(127);
+                self.send_program().await;
+            }
+            FnAction::Program(program) => {
+                self.program = program.min(127);
+                self.send_program().await;
+            }
+            FnAction::BankStep(step) => {
+                self.bank = (self.bank as i32 + step as i32).clamp(0, 0x3fff) as u16;
+                self.send_program().await;
+            }
+            FnAction::Bank(bank) => {
+                self.bank = bank.min(0x3fff);
+                self.send_program().await;
+            }
+            FnAction::PedalPolarity => {
+                let inverted = !PEDAL_INVERT.load(Ordering::Relaxed);
+                defmt::info!("pedal polarity inverted: {}", inverted);
+                PEDAL_INVERT.store(inverted, Ordering::Relaxed);
+            }
+            FnAction::ControlChange(cc, value) => {
+                self.control_change(cc, value).await;
+            }
+            FnAction::Perform(mode) => perform::set_mode(mode).await,
+            FnAction::LearnChord => perform::learn_chord().await,
+            FnAction::ArpPattern(pattern) => perform::set_arp_pattern(pattern),
+            FnAction::Tempo(bpm) => {
+                clock::set_tempo(bpm);
+                defmt::info!("tempo set to {}", clock::tempo());
+            }
+            FnAction::TempoStep(step) => {
+                clock::set_tempo(clock::tempo().saturating_add_signed(step as i16));
+                defmt::info!("tempo set to {}", clock::tempo());
+            }
+            FnAction::TapTempo => clock::tap(),
+            FnAction::Transport => clock::toggle().await,
+            FnAction::Metronome(metronome) => clock::set_metronome(metronome),
+            FnAction::Looper(cmd) => looper::command(cmd).await,
+            FnAction::HidMode => {
+                self.hid_mode = !self.hid_mode;
+                defmt::info!("hid mode: {}", self.hid_mode);
+            }
+        }
+    }
+
+    /// Apply all settings in a macro.
+    async fn run_macro(&mut self, id: u8) {
+        let Some(actions) = self.macros.get(id as usize) else {
+            defmt::warn!("macro {} is not defined", id);
+            return;
+        };
+        for action in actions.iter() {
+            self.apply(*action).await;
+        }
+    }
+}
+
+impl Config {
+    /// Send the current bank and program to every zone.
+    async fn send_program(&self) {
+        defmt::info!("bank {} program {}", self.bank, self.program);
+        for zone in self.zones.iter() {
+            midi::MidiChannel::new(zone.channel)
+                .select_program(self.bank, self.program)
+                .await;
+        }
+    }
+}
+
+/// Settings requested by other tasks, applied by the key matrix scanner.
+static ACTIONS: Channel<ThreadModeRawMutex, FnAction, 4> = Channel::new();
+
+/// Task for a button (active low) on a GPIO pin that changes a setting when pressed.
+///
+/// For example, `FnAction::ProgramStep(1)` makes a "next program" button.
+#[embassy_executor::task(pool_size = 4)]
+pub async fn button(pin: gpio::AnyPin, action: FnAction) {
+    let mut inp = gpio::Input::new(pin, gpio::Pull::Up);
+    loop {
+        inp.wait_for_low().await;
+        ACTIONS.send(action).await;
+        // debounce
+        Timer::after_millis(20).await;
+        inp.wait_for_high().await;
+        Timer::after_millis(20).await;
+    }
+}
+
+/// Flips the polarity of the pedals. Set from function-key mode.
+static PEDAL_INVERT: AtomicBool = AtomicBool::new(false);
+
 /// Task to handle pedals in MIDI
 ///
 /// `norm_open` represents a normally open switch
 #[embassy_executor::task]
 pub async fn pedal(pedal: midi::Controller, pin: gpio::AnyPin, norm_state: NormalState) {
     let mut inp = gpio::Input::new(pin, gpio::Pull::Up);
-    let chan = midi::MidiChannel::new(0);
+    let chan = midi::MidiChannel::new(0).on_cable(midi::Cable::Controls);
     loop {
-        let (off_val, on_val) = match norm_state {
-            NormalState::NO => (0, 64),
-            NormalState::NC => (64, 0),
+        let (off_val, on_val) = match (norm_state, PEDAL_INVERT.load(Ordering::Relaxed)) {
+            (NormalState::NO, false) | (NormalState::NC, true) => (0, 64),
+            (NormalState::NC, false) | (NormalState::NO, true) => (64, 0),
         };
         inp.wait_for_low().await;
         chan.controller(pedal, on_val).await;
@@ -74,6 +627,8 @@ pub struct KeyMatrix<const N_ROWS: usize, const N_COLS: usize> {
     /// Input pins at the left of each row
     row_pins: [u8; N_ROWS],
     keymap: [[midi::KeyAction; N_COLS]; N_ROWS],
+    /// Re-strike notes when N2 opens and closes again while N1 stays closed.
+    retrigger: bool,
 }
 
 impl<const N_ROWS: usize, const N_COLS: usize> KeyMatrix<N_ROWS, N_COLS> {
@@ -92,25 +647,56 @@ impl<const N_ROWS: usize, const N_COLS: usize> KeyMatrix<N_ROWS, N_COLS> {
             col_pins,
             row_pins,
             keymap,
+            retrigger: false,
         }
     }
 
-    pub async fn scan(&mut self, mut pin_driver: pins::TransparentPins, config: Config) {
+    /// Re-strike notes when N2 opens and closes again while N1 stays closed (double
+    /// escapement), instead of only when the key is fully released.
+    ///
+    /// Only enable this if this keymap's N1 switches reliably stay closed until the key is almost
+    /// fully up.
+    pub fn with_retrigger(mut self, retrigger: bool) -> Self {
+        self.retrigger = retrigger;
+        self
+    }
+
+    pub async fn scan<const N_EXT: usize, const N_ONBOARD: usize>(
+        &mut self,
+        mut pin_driver: pins::TransparentPins<N_EXT, N_ONBOARD>,
+        mut config: Config,
+    ) {
         for i in pin_driver.pins {
             unwrap(pin_driver.set_input(i)).await;
             unwrap(pin_driver.set_pull(i, gpio::Pull::Up)).await;
         }
 
-        let chan = midi::MidiChannel::new(0);
-        const MAX_NOTES: usize = 128;
+        let fn_pin = config
+            .fn_pin
+            .take()
+            .map(|pin| gpio::Input::new(pin, gpio::Pull::Up));
 
-        // (for velocity detection) moment key is first touched
-        let mut note_first: [Option<Instant>; MAX_NOTES] = [None; MAX_NOTES];
-        // (for debouncing) moment note was last on
-        let mut note_on: [Option<Instant>; MAX_NOTES] = [None; MAX_NOTES];
+        let mut notes = [NoteState::new(); MAX_NOTES];
+        // state of switches that aren't notes
+        let mut switch_on = [[false; N_COLS]; N_ROWS];
+        // state of `KeyAction::Toggle` switches
+        let mut toggled = [[false; N_COLS]; N_ROWS];
+
+        // only the extenders with row pins need to be read
+        let mut rows = pins::PinSet::new();
+        for row in self.row_pins {
+            rows.insert(row);
+        }
 
         let mut counter = 0;
         let mut prof_col_idx = 0;
+        // scans left in which holding the function-key gesture reboots to BOOTSEL
+        let mut startup_scans = 10;
+        // last time any switch was down
+        let mut last_active = Instant::now();
+        let mut last_check = Instant::now();
+        // idle waits since the last real scan
+        let mut idle_waits = 0;
 
         defmt::debug!("using {} columns", N_COLS);
 
@@ -122,9 +708,52 @@ impl<const N_ROWS: usize, const N_COLS: usize> KeyMatrix<N_ROWS, N_COLS> {
             let mut _prof_time_last_col = _prof_start;
             let mut _prof_dur_col = Duration::from_ticks(0);
 
+            if let Some(pin) = &fn_pin {
+                let held = pin.is_low();
+                let ev = config.fn_mode.as_mut().and_then(|f| f.shift_pin(held));
+                if let Some(ev) = ev {
+                    config.fn_event(ev, &mut notes).await;
+                }
+            }
+
+            while let Ok(action) = ACTIONS.try_receive() {
+                config.apply(action).await;
+            }
+
+            if last_check.elapsed() > Duration::from_millis(EXTENDER_CHECK_INTERVAL_MS) {
+                pin_driver.check_extenders().await;
+                last_check = Instant::now();
+            }
+
+            if pin_driver.has_interrupt_pin()
+                && last_active.elapsed() > Duration::from_millis(IDLE_AFTER_MS)
+                && idle_waits < IDLE_SCAN_EVERY
+            {
+                // nobody is playing: drive all the columns at once, and let the extenders tell us
+                // when a row goes low instead of scanning
+                for col in self.col_pins {
+                    unwrap(pin_driver.set_output(col)).await;
+                }
+                let timeout = Duration::from_millis(IDLE_WAIT_MS);
+                let woke = unwrap(pin_driver.wait_for_low(&rows, timeout).await).await;
+                for col in self.col_pins {
+                    unwrap(pin_driver.set_input(col)).await;
+                }
+                idle_waits += 1;
+                if woke {
+                    defmt::debug!("waking up from idle");
+                    last_active = Instant::now();
+                }
+                continue;
+            }
+            idle_waits = 0;
+
+            // some switch was just pressed
+            let mut any_pressed = false;
+
             for (i, col) in self.col_pins.iter().enumerate() {
                 unwrap(pin_driver.set_output(*col)).await;
-                let input = unwrap(pin_driver.read_all()).await;
+                let input = unwrap(pin_driver.read_pins(&rows).await).await;
                 unwrap(pin_driver.set_input(*col)).await;
 
                 if profile && i == prof_col_idx {
@@ -132,22 +761,32 @@ impl<const N_ROWS: usize, const N_COLS: usize> KeyMatrix<N_ROWS, N_COLS> {
                 }
 
                 // values that are logical ON
-                let mask = input ^ (((1 << pin_driver.n_usable_pins()) - 1) ^ (1 << col));
+                let mut idle = pins::PinSet::first(pin_driver.n_usable_pins());
+                idle.remove(*col);
+                let mask = input ^ idle;
                 for (j, row) in self.row_pins.iter().enumerate() {
                     let key_action = self.keymap[j][i];
-                    let key_active = mask & (1 << row) != 0;
+                    let key_active = mask.contains(*row);
+                    let was_on = switch_on[j][i];
+                    switch_on[j][i] = key_active;
+                    let switch_pressed = key_active && !was_on;
+                    any_pressed |= switch_pressed;
+                    if key_active {
+                        last_active = Instant::now();
+                    }
                     match key_action {
                         midi::KeyAction::N1(note) => {
+                            let state = &mut notes[note as usize];
                             if key_active {
-                                if note_first[note as usize].is_none() {
-                                    note_first[note as usize] = Some(Instant::now());
+                                if state.first.is_none() {
+                                    state.first = Some(Instant::now());
                                 }
-                            } else if note_first[note as usize].is_some() {
-                                note_first[note as usize] = None;
+                            } else if state.first.is_some() {
+                                state.first = None;
 
-                                if let Some(note_on_time) = note_on[note as usize] {
-                                    note_on[note as usize] = None;
-                                    chan.note_off(note, 0).await;
+                                if let Some(note_on_time) = state.on {
+                                    state.on = None;
+                                    config.release(note, &mut notes).await;
                                     defmt::debug!(
                                         "turned off note {} after {} us",
                                         note,
@@ -157,41 +796,104 @@ impl<const N_ROWS: usize, const N_COLS: usize> KeyMatrix<N_ROWS, N_COLS> {
                             }
                         }
                         midi::KeyAction::N2(note) => {
+                            let state = notes[note as usize];
                             if key_active {
-                                if note_first[note as usize].is_some()
-                                    && note_on[note as usize].is_none()
-                                {
+                                if let (Some(first), None) = (state.first, state.on) {
                                     // microsecond duration of keypress
-                                    let dur =
-                                        note_first[note as usize].unwrap().elapsed().as_micros();
-                                    let velocity = match config.velocity_prof {
-                                        VelocityProfile::Heavy => velocity_heavy(dur),
-                                        VelocityProfile::Linear => velocity_linear(dur),
-                                        VelocityProfile::Light => velocity_light(dur),
-                                    };
-                                    defmt::debug!(
-                                        "{} velocity {} from dur {}us",
-                                        note,
-                                        velocity,
-                                        dur
-                                    );
-                                    note_on[note as usize] = Some(Instant::now());
-                                    chan.note_on(note, velocity).await;
-                                } else if note_on[note as usize].is_some() {
+                                    let dur = first.elapsed().as_micros();
+                                    defmt::debug!("{} from dur {}us", note, dur);
+                                    midi::key_timing(note, dur).await;
+                                    config.press(note, Strike::Timed(dur), &mut notes).await;
+                                    let state = &mut notes[note as usize];
+                                    state.on = Some(Instant::now());
+                                    state.bottom = state.on;
+                                } else if state.on.is_some() {
+                                    let state = &mut notes[note as usize];
                                     // keep refreshing the note
-                                    note_on[note as usize] = Some(Instant::now());
+                                    state.on = Some(Instant::now());
+                                    // time N2 was open for, if it just closed again
+                                    let rearm_us =
+                                        state.rearm.take().map(|t| t.elapsed().as_micros());
+                                    let bounced =
+                                        matches!(rearm_us, Some(us) if us < REARM_DEBOUNCE_US);
+                                    let restrike = state.bottom.is_none() && !bounced;
+                                    if state.bottom.is_none() {
+                                        state.bottom = state.on;
+                                    }
+                                    match rearm_us {
+                                        Some(open_us) if restrike && self.retrigger => {
+                                            // N1 stays closed during a re-strike, so the way
+                                            // down can't be timed by itself: the only
+                                            // measurement is how long N2 was open, while the
+                                            // key rose about the N1 to N2 distance and came
+                                            // back down. Half of that is the average time for
+                                            // that distance, which is on the same scale as the
+                                            // N1 to N2 time of a normal press, so the velocity
+                                            // curve applies as is. A faster re-strike still
+                                            // gives a shorter time.
+                                            let dur = open_us / 2;
+                                            defmt::debug!("{} re-struck from dur {}us", note, dur);
+                                            config
+                                                .retrigger(note, Strike::Timed(dur), &mut notes)
+                                                .await;
+                                        }
+                                        _ => config.held(state, restrike).await,
+                                    }
                                 }
+                            } else {
+                                let state = &mut notes[note as usize];
+                                if state.on.is_some() && state.bottom.is_some() {
+                                    // key rose to the N1 level
+                                    state.rearm = Some(Instant::now());
+                                }
+                                state.bottom = None;
                             }
                         }
                         midi::KeyAction::N(note, velocity) => {
                             if key_active {
-                                if note_on[note as usize].is_none() {
-                                    note_on[note as usize] = Some(Instant::now());
-                                    chan.note_on(note, velocity).await;
+                                if notes[note as usize].on.is_none() {
+                                    config
+                                        .press(note, Strike::Fixed(velocity), &mut notes)
+                                        .await;
+                                    notes[note as usize].on = Some(Instant::now());
                                 }
-                            } else if note_on[note as usize].is_some() {
-                                note_on[note as usize] = None;
-                                chan.note_off(note, 0).await;
+                            } else if notes[note as usize].on.is_some() {
+                                notes[note as usize].on = None;
+                                config.release(note, &mut notes).await;
+                            }
+                        }
+                        midi::KeyAction::Program(program) => {
+                            if switch_pressed {
+                                config.apply(FnAction::Program(program)).await;
+                            }
+                        }
+                        midi::KeyAction::Bank(bank) => {
+                            if switch_pressed {
+                                config.apply(FnAction::Bank(bank)).await;
+                            }
+                        }
+                        midi::KeyAction::Cc(cc, on_value, off_value) => {
+                            if switch_pressed {
+                                config.control_change(cc, on_value).await;
+                            } else if was_on && !key_active {
+                                config.control_change(cc, off_value).await;
+                            }
+                        }
+                        midi::KeyAction::Toggle(cc) => {
+                            if switch_pressed {
+                                toggled[j][i] = !toggled[j][i];
+                                let value = if toggled[j][i] { 127 } else { 0 };
+                                config.control_change(cc, value).await;
+                            }
+                        }
+                        midi::KeyAction::Transpose(step) => {
+                            if switch_pressed {
+                                config.apply(FnAction::TransposeStep(step)).await;
+                            }
+                        }
+                        midi::KeyAction::Macro(id) => {
+                            if switch_pressed {
+                                config.run_macro(id).await;
                             }
                         }
                         midi::KeyAction::NOP => {}
@@ -211,6 +913,23 @@ impl<const N_ROWS: usize, const N_COLS: usize> KeyMatrix<N_ROWS, N_COLS> {
                 // );
             }
 
+            if startup_scans > 0 {
+                startup_scans -= 1;
+                let held = config.fn_mode.as_ref().is_some_and(|f| f.is_active());
+                if config.bootsel_gesture && held {
+                    bootsel::reboot_to_bootsel().await;
+                }
+            }
+
+            if usb::suspended() {
+                if any_pressed && config.wakeup {
+                    usb::remote_wakeup();
+                }
+                // nothing is being played to, so scan slowly
+                Timer::after_millis(SUSPENDED_SCAN_INTERVAL_MS).await;
+                continue;
+            }
+
             // relinquish to other tasks for a moment
             Timer::after_micros(50).await;
         }
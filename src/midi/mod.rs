@@ -19,11 +19,20 @@
 //! MIDI utilities
 //!
 //! This sets up a queue of MIDI packets to send on behalf of other tasks.
+//!
+//! The device has a few virtual cables (see [`Cable`]), so that the keys, the pedals and
+//! diagnostics show up as separate MIDI ports.
+
+pub mod sysex;
 
 use embassy_rp::usb::{Driver, Instance};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
-use embassy_usb::{class::midi::MidiClass, driver::EndpointError};
+use embassy_usb::{
+    class::midi::{Receiver, Sender},
+    driver::EndpointError,
+};
 use defmt::Format;
+use portable_atomic::{AtomicBool, Ordering};
 
 ////////////////////////////////
 ////////////////////////////////
@@ -45,16 +54,18 @@ impl NoteMsg {
 
 #[derive(Copy, Clone, Debug, Format)]
 pub enum Controller {
+    BankSelect = 0,
+    BankSelectLsb = 32,
     SustainPedal = 64,
 }
 
 struct ControllerMsg {
-    controller: Controller,
+    controller: u8,
     value: u8,
 }
 
 impl ControllerMsg {
-    fn new(controller: Controller, value: u8) -> Self {
+    fn new(controller: u8, value: u8) -> Self {
         ControllerMsg { controller, value }
     }
 }
@@ -62,11 +73,21 @@ impl ControllerMsg {
 enum MsgType {
     Note(NoteMsg),
     Controller(ControllerMsg),
+    Program(u8),
+    KeyPressure(Note, u8),
+    ChannelPressure(u8),
+    /// System real-time message (status byte), e.g. clock.
+    Realtime(u8),
+    /// Channel message that is already encoded (status and data bytes).
+    Raw([u8; 3]),
+    /// System exclusive message, including F0 and F7 (buffer, length).
+    SysEx([u8; 12], usize),
 }
 
 struct MidiMsg {
     msg: MsgType,
     channel: u8,
+    cable: Cable,
 }
 
 impl MidiMsg {
@@ -74,8 +95,13 @@ impl MidiMsg {
         MidiMsg {
             msg,
             channel: channel & 0xf,
+            cable: Cable::Keys,
         }
     }
+
+    fn on_cable(self, cable: Cable) -> Self {
+        MidiMsg { cable, ..self }
+    }
 }
 
 ////////////////////////////////
@@ -88,6 +114,7 @@ impl MidiMsg {
 ///
 /// See src/midi/note_def.py for how this is generated
 #[derive(Clone, Copy, Debug, Format)]
+#[repr(u8)]
 pub enum Note {
     A0 = 21,
     AS0 = 22,
@@ -190,6 +217,46 @@ pub enum Note {
     B8 = 119,
 }
 
+impl TryFrom<u8> for Note {
+    type Error = u8;
+
+    /// Convert a raw MIDI note number to a [`Note`], if it is in range.
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if (Note::A0 as u8..=Note::B8 as u8).contains(&value) {
+            // SAFETY: `Note` is `repr(u8)` and its discriminants are contiguous over this range
+            Ok(unsafe { core::mem::transmute::<u8, Note>(value) })
+        } else {
+            Err(value)
+        }
+    }
+}
+
+impl Note {
+    /// Shift the note by some semitones. Returns `None` if the result is out of range.
+    pub fn transpose(self, semitones: i8) -> Option<Note> {
+        let val = self as i16 + semitones as i16;
+        if !(0..=127).contains(&val) {
+            return None;
+        }
+        Note::try_from(val as u8).ok()
+    }
+}
+
+/// Virtual cable (MIDI port) messages are sent on.
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum Cable {
+    /// Notes, aftertouch and program changes from the keyboard.
+    Keys = 0,
+    /// Pedals and controllers.
+    Controls = 1,
+    /// Diagnostic stream (e.g. key timings), as SysEx. Off unless enabled with
+    /// [`set_diagnostics`].
+    Diagnostics = 2,
+}
+
+/// Amount of virtual cables from the device to the host.
+pub const N_CABLES: u8 = 3;
+
 #[derive(Clone, Copy)]
 pub enum KeyAction {
     /// Switch that is first triggered when pressing a key.
@@ -198,6 +265,18 @@ pub enum KeyAction {
     N2(Note),
     /// Basic switch with fixed velocity. Be careful not to mix with actions with velocity detection.
     N(Note, u8),
+    /// Switch that sends a Program Change, in the current bank.
+    Program(u8),
+    /// Switch that selects a bank (14-bit), and sends the current program in it.
+    Bank(u16),
+    /// Switch that sends a Control Change (controller number, value on press, value on release).
+    Cc(u8, u8, u8),
+    /// Switch that flips a controller between 0 and 127 on every press.
+    Toggle(u8),
+    /// Switch that transposes the keyboard by some semitones on every press.
+    Transpose(i8),
+    /// Switch that runs a macro from [`crate::matrix::Config::macros`].
+    Macro(u8),
     /// NOP
     NOP,
 }
@@ -215,25 +294,135 @@ impl From<EndpointError> for Disconnected {
 
 static MIDI_QUEUE: Channel<ThreadModeRawMutex, MidiMsg, 10> = Channel::new();
 
+/// Throw away queued messages until cancelled, so that tasks sending MIDI don't block while the
+/// host is asleep.
+pub async fn discard() {
+    loop {
+        MIDI_QUEUE.receive().await;
+    }
+}
+
 /// Handle sending MIDI until connection breaks
 pub async fn midi_session<'d, T: Instance + 'd>(
-    midi: &mut MidiClass<'d, Driver<'d, T>>,
+    midi: &mut Sender<'d, Driver<'d, T>>,
 ) -> Result<(), Disconnected> {
     loop {
         let msg = MIDI_QUEUE.receive().await;
-        match msg.msg {
+        // only record what's played (not the looper's own playback)
+        let record = matches!(
+            msg.msg,
+            MsgType::Note(_)
+                | MsgType::Controller(_)
+                | MsgType::Program(_)
+                | MsgType::KeyPressure(..)
+                | MsgType::ChannelPressure(_)
+        );
+        let mut packet = match msg.msg {
             MsgType::Note(note) => {
                 let status: u8 = (if note.on { 0b1001_0000 } else { 0b1000_0000 }) | msg.channel;
                 // i'll be honest i have no idea where the first number here comes from
                 let packet = [8, status, note.note as u8, note.velocity];
                 defmt::trace!("midi_session: note {:?}", packet);
-                midi.write_packet(&packet).await?
+                packet
             }
             MsgType::Controller(ctrl) => {
                 let status: u8 = (0b1011_0000) | msg.channel;
-                let packet = [8, status, ctrl.controller as u8, ctrl.value];
+                let packet = [8, status, ctrl.controller & 0x7f, ctrl.value];
                 defmt::trace!("midi_session: control {:?}", packet);
-                midi.write_packet(&packet).await?
+                packet
+            }
+            MsgType::Program(program) => {
+                let status: u8 = (0b1100_0000) | msg.channel;
+                // program change is only two bytes, so the code index number matters here
+                let packet = [0xc, status, program & 0x7f, 0];
+                defmt::trace!("midi_session: program {:?}", packet);
+                packet
+            }
+            MsgType::KeyPressure(note, pressure) => {
+                let status: u8 = (0b1010_0000) | msg.channel;
+                let packet = [0xa, status, note as u8, pressure & 0x7f];
+                defmt::trace!("midi_session: key pressure {:?}", packet);
+                packet
+            }
+            MsgType::ChannelPressure(pressure) => {
+                let status: u8 = (0b1101_0000) | msg.channel;
+                // two byte message, like program change
+                let packet = [0xd, status, pressure & 0x7f, 0];
+                defmt::trace!("midi_session: channel pressure {:?}", packet);
+                packet
+            }
+            MsgType::Realtime(status) => {
+                // single byte, no channel
+                let packet = [0xf, status, 0, 0];
+                defmt::trace!("midi_session: real-time {:?}", packet);
+                packet
+            }
+            MsgType::Raw(raw) => {
+                // the code index number is the same as the message type for channel messages
+                let packet = [raw[0] >> 4, raw[0], raw[1], raw[2]];
+                defmt::trace!("midi_session: raw {:?}", packet);
+                packet
+            }
+            MsgType::SysEx(buf, len) => {
+                write_sysex(midi, msg.cable, &buf[..len]).await?;
+                continue;
+            }
+        };
+        // the cable number goes in the high nibble
+        packet[0] |= (msg.cable as u8) << 4;
+        midi.write_packet(&packet).await?;
+        if record {
+            let msg = [packet[1], packet[2], packet[3]];
+            crate::looper::capture(msg);
+            crate::history::capture(msg);
+        }
+    }
+}
+
+/// Send a SysEx message, three bytes per packet.
+async fn write_sysex<'d, T: Instance + 'd>(
+    midi: &mut Sender<'d, Driver<'d, T>>,
+    cable: Cable,
+    data: &[u8],
+) -> Result<(), Disconnected> {
+    let mut chunks = data.chunks(3).peekable();
+    while let Some(chunk) = chunks.next() {
+        // 0x4 continues, 0x5 to 0x7 end with one to three bytes
+        let cin = if chunks.peek().is_some() {
+            0x4
+        } else {
+            0x4 + chunk.len() as u8
+        };
+        let mut packet = [((cable as u8) << 4) | cin, 0, 0, 0];
+        packet[1..=chunk.len()].copy_from_slice(chunk);
+        defmt::trace!("midi_session: sysex {:?}", packet);
+        midi.write_packet(&packet).await?;
+    }
+    Ok(())
+}
+
+/// Handle MIDI received from the host until connection breaks
+pub async fn midi_receive<'d, T: Instance + 'd>(
+    midi: &mut Receiver<'d, Driver<'d, T>>,
+) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+    let mut sysex = sysex::Reader::new();
+    loop {
+        let n = midi.read_packet(&mut buf).await?;
+        for packet in buf[..n].chunks_exact(4) {
+            match packet[0] & 0xf {
+                // real-time messages are single bytes
+                0xf => {
+                    defmt::trace!("midi_receive: real-time {:?}", packet);
+                    crate::clock::realtime(packet[1]).await;
+                }
+                // SysEx, split over many packets
+                0x4..=0x7 => {
+                    if let Some(msg) = sysex.push(packet) {
+                        sysex::handle(msg).await;
+                    }
+                }
+                _ => {}
             }
         }
     }
@@ -242,40 +431,125 @@ pub async fn midi_session<'d, T: Instance + 'd>(
 /// Public MIDI interface that can be used to send notes/control packets.
 pub struct MidiChannel {
     channel: u8,
+    cable: Cable,
 }
 
 impl MidiChannel {
+    /// Channel on the [`Cable::Keys`] cable.
     pub fn new(channel: u8) -> Self {
-        MidiChannel { channel }
+        MidiChannel {
+            channel,
+            cable: Cable::Keys,
+        }
+    }
+
+    /// Send on another cable.
+    pub fn on_cable(self, cable: Cable) -> Self {
+        MidiChannel { cable, ..self }
+    }
+
+    fn msg(&self, msg: MsgType) -> MidiMsg {
+        MidiMsg::new(msg, self.channel).on_cable(self.cable)
     }
 
     /// MIDI Note-On
     pub async fn note_on(&self, note: Note, velocity: u8) {
         MIDI_QUEUE
-            .send(MidiMsg::new(
-                MsgType::Note(NoteMsg::new(true, note, velocity)),
-                self.channel,
-            ))
+            .send(self.msg(MsgType::Note(NoteMsg::new(true, note, velocity))))
             .await;
     }
 
     /// MIDI Note-Off
     pub async fn note_off(&self, note: Note, velocity: u8) {
         MIDI_QUEUE
-            .send(MidiMsg::new(
-                MsgType::Note(NoteMsg::new(false, note, velocity)),
-                self.channel,
-            ))
+            .send(self.msg(MsgType::Note(NoteMsg::new(false, note, velocity))))
             .await;
     }
 
     /// MIDI Controller (e.g. sustain pedal on/off)
     pub async fn controller(&self, ctrl: Controller, value: u8) {
+        self.control_change(ctrl as u8, value).await;
+    }
+
+    /// MIDI Control Change, by controller number
+    pub async fn control_change(&self, cc: u8, value: u8) {
+        MIDI_QUEUE
+            .send(self.msg(MsgType::Controller(ControllerMsg::new(cc, value))))
+            .await;
+    }
+
+    /// MIDI Program Change
+    pub async fn program_change(&self, program: u8) {
+        MIDI_QUEUE.send(self.msg(MsgType::Program(program))).await;
+    }
+
+    /// MIDI Polyphonic Key Pressure (aftertouch)
+    pub async fn key_pressure(&self, note: Note, pressure: u8) {
+        MIDI_QUEUE
+            .send(self.msg(MsgType::KeyPressure(note, pressure)))
+            .await;
+    }
+
+    /// MIDI Channel Pressure (aftertouch)
+    pub async fn channel_pressure(&self, pressure: u8) {
         MIDI_QUEUE
-            .send(MidiMsg::new(
-                MsgType::Controller(ControllerMsg::new(ctrl, value)),
-                self.channel,
-            ))
+            .send(self.msg(MsgType::ChannelPressure(pressure)))
+            .await;
+    }
+
+    /// MIDI Bank Select (CC0 and CC32), then Program Change
+    pub async fn select_program(&self, bank: u16, program: u8) {
+        self.controller(Controller::BankSelect, ((bank >> 7) & 0x7f) as u8)
             .await;
+        self.controller(Controller::BankSelectLsb, (bank & 0x7f) as u8)
+            .await;
+        self.program_change(program).await;
+    }
+}
+
+/// Send a system real-time message (e.g. 0xf8 for clock).
+pub async fn realtime(status: u8) {
+    MIDI_QUEUE
+        .send(MidiMsg::new(MsgType::Realtime(status), 0))
+        .await;
+}
+
+/// Send an encoded channel message (status and data bytes). These are not recorded by the looper.
+pub async fn raw(msg: [u8; 3]) {
+    MIDI_QUEUE.send(MidiMsg::new(MsgType::Raw(msg), 0)).await;
+}
+
+/// Diagnostics are enabled.
+static DIAGNOSTICS: AtomicBool = AtomicBool::new(false);
+
+/// Turn the diagnostic stream on or off.
+pub fn set_diagnostics(on: bool) {
+    DIAGNOSTICS.store(on, Ordering::Relaxed);
+}
+
+/// Send key timing on the diagnostics cable, if enabled: the time in microseconds between the
+/// two switches of a key closing.
+///
+/// The message is `F0 7D 10 <note> <t3> <t2> <t1> <t0> F7`, with the time split in 7-bit groups,
+/// most significant first.
+pub async fn key_timing(note: Note, us: u64) {
+    if !DIAGNOSTICS.load(Ordering::Relaxed) {
+        return;
     }
+    let us = us.min(0x0fff_ffff) as u32;
+    let mut buf = [0u8; 12];
+    buf[..9].copy_from_slice(&[
+        0xf0,
+        0x7d,
+        0x10,
+        note as u8,
+        (us >> 21) as u8 & 0x7f,
+        (us >> 14) as u8 & 0x7f,
+        (us >> 7) as u8 & 0x7f,
+        us as u8 & 0x7f,
+        0xf7,
+    ]);
+    MIDI_QUEUE
+        .send(MidiMsg::new(MsgType::SysEx(buf, 9), 0).on_cable(Cable::Diagnostics))
+        .await;
 }
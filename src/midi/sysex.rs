@@ -0,0 +1,129 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! SysEx commands from the host.
+//!
+//! Messages have the form `F0 7D <command> <data...> F7`, where 7D is the manufacturer ID
+//! reserved for non-commercial use. Commands:
+//!
+//! - `01 <msb> <lsb>`: set the tempo, in beats per minute (14 bits, 7 per byte).
+//! - `02 <on>`: turn the diagnostic stream on (1) or off (0), see [`crate::midi::key_timing`].
+//! - `03`: reboot into BOOTSEL mode, to update the firmware.
+
+use crate::bootsel;
+use crate::clock;
+use crate::midi;
+
+/// Manufacturer ID for non-commercial use.
+const MANUFACTURER: u8 = 0x7d;
+
+/// Longest SysEx message kept (excluding F0 and F7). Longer messages are ignored.
+const MAX_LEN: usize = 32;
+
+/// Set the tempo.
+const CMD_TEMPO: u8 = 0x01;
+/// Turn diagnostics on or off.
+const CMD_DIAGNOSTICS: u8 = 0x02;
+/// Reboot into BOOTSEL mode.
+const CMD_BOOTSEL: u8 = 0x03;
+
+/// Reassembles SysEx messages from USB-MIDI packets.
+pub struct Reader {
+    buf: heapless::Vec<u8, MAX_LEN>,
+    /// A message is being received.
+    active: bool,
+    /// The current message did not fit in the buffer.
+    overflow: bool,
+}
+
+impl Reader {
+    pub fn new() -> Self {
+        Reader {
+            buf: heapless::Vec::new(),
+            active: false,
+            overflow: false,
+        }
+    }
+
+    /// Feed a USB-MIDI packet with a SysEx code index number (0x4 to 0x7).
+    ///
+    /// Returns the message between F0 and F7 once it is complete.
+    pub fn push(&mut self, packet: &[u8]) -> Option<&[u8]> {
+        let (len, end) = match packet[0] & 0xf {
+            // start or continue
+            0x4 => (3, false),
+            // ends with one, two or three bytes
+            0x5 => (1, true),
+            0x6 => (2, true),
+            0x7 => (3, true),
+            _ => return None,
+        };
+
+        for byte in packet[1..=len].iter() {
+            match *byte {
+                0xf0 => {
+                    self.buf.clear();
+                    self.active = true;
+                    self.overflow = false;
+                }
+                0xf7 => {}
+                b => {
+                    if self.active && self.buf.push(b).is_err() {
+                        self.overflow = true;
+                    }
+                }
+            }
+        }
+
+        if !end || !self.active {
+            return None;
+        }
+        self.active = false;
+        if self.overflow {
+            defmt::warn!("sysex: message too long");
+            return None;
+        }
+        Some(&self.buf)
+    }
+}
+
+impl Default for Reader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run a SysEx command (message between F0 and F7).
+pub async fn handle(msg: &[u8]) {
+    let [MANUFACTURER, cmd, data @ ..] = msg else {
+        // not for us
+        return;
+    };
+    match (*cmd, data) {
+        (CMD_TEMPO, [msb, lsb]) => {
+            clock::set_tempo(((*msb as u16) << 7) | *lsb as u16);
+            defmt::info!("sysex: tempo set to {}", clock::tempo());
+        }
+        (CMD_DIAGNOSTICS, [on]) => {
+            midi::set_diagnostics(*on != 0);
+            defmt::info!("sysex: diagnostics {}", *on != 0);
+        }
+        (CMD_BOOTSEL, []) => bootsel::reboot_to_bootsel().await,
+        _ => defmt::warn!("sysex: unknown command {:?}", msg),
+    }
+}
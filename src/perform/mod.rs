@@ -0,0 +1,117 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Performance modes: hold/latch, chord memory and arpeggiator.
+//!
+//! These operate on note events once zones have been applied (so every note already has a MIDI
+//! channel), right before they are sent. The state machine is in [`state`]; this module connects
+//! it to the rest of the firmware.
+
+use crate::midi;
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+
+pub mod state;
+
+pub use state::{
+    ArpConfig, ArpPattern, NoteEvent, Output, PerformMode, Performer, MAX_CHORD, MAX_HELD,
+};
+
+////////////////////////////////
+////////////////////////////////
+// Firmware interface
+////////////////////////////////
+////////////////////////////////
+
+static PERFORMER: Mutex<ThreadModeRawMutex, RefCell<Performer>> =
+    Mutex::new(RefCell::new(Performer::new(ArpConfig::DEFAULT)));
+
+/// Send note events from the performer.
+async fn send(out: Output) {
+    for ev in out {
+        let Ok(note) = midi::Note::try_from(ev.note) else {
+            continue;
+        };
+        let chan = midi::MidiChannel::new(ev.channel);
+        if ev.on {
+            chan.note_on(note, ev.velocity).await;
+        } else {
+            chan.note_off(note, 0).await;
+        }
+    }
+}
+
+/// Run something on the performer, then send the notes it outputs.
+async fn with_performer(f: impl FnOnce(&mut Performer) -> Output) {
+    let out = PERFORMER.lock(|p| {
+        let mut performer = p.borrow_mut();
+        let learning = performer.is_learning();
+        let out = f(&mut performer);
+        if learning && !performer.is_learning() && performer.mode() == PerformMode::Chord {
+            defmt::info!(
+                "perform: learned chord of {} notes",
+                performer.chord().len()
+            );
+        }
+        out
+    });
+    send(out).await;
+}
+
+/// Play a Note-On through the performance modes.
+pub async fn note_on(channel: u8, note: midi::Note, velocity: u8) {
+    with_performer(|p| p.note_on(channel, note as u8, velocity)).await;
+}
+
+/// Play a Note-Off through the performance modes.
+pub async fn note_off(channel: u8, note: midi::Note) {
+    with_performer(|p| p.note_off(channel, note as u8)).await;
+}
+
+/// Advance the performance modes by a clock tick.
+pub async fn tick() {
+    with_performer(|p| p.tick()).await;
+}
+
+/// Restart the arpeggio pattern.
+pub async fn restart() {
+    with_performer(|p| p.restart()).await;
+}
+
+/// Change the performance mode.
+pub async fn set_mode(mode: PerformMode) {
+    defmt::info!("perform: mode {}", mode);
+    with_performer(|p| p.set_mode(mode)).await;
+}
+
+/// Store the next chord played, then play it in chord mode.
+pub async fn learn_chord() {
+    defmt::info!("perform: learning chord");
+    with_performer(|p| p.learn_chord()).await;
+}
+
+/// Change the arpeggiator settings.
+pub fn set_arp(config: ArpConfig) {
+    PERFORMER.lock(|p| p.borrow_mut().arp = config);
+}
+
+/// Change only the arpeggiator pattern.
+pub fn set_arp_pattern(pattern: ArpPattern) {
+    defmt::info!("perform: arp pattern {}", pattern);
+    PERFORMER.lock(|p| p.borrow_mut().arp.pattern = pattern);
+}
@@ -0,0 +1,681 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Performance mode state machine.
+//!
+//! [`Performer`] takes note events and clock ticks, and returns the note events to send. It
+//! doesn't depend on the hardware, so it can be tested on the host.
+
+use core::cmp::max;
+use defmt::Format;
+
+/// Maximum amount of keys tracked at once.
+pub const MAX_HELD: usize = 10;
+/// Maximum amount of notes in a stored chord.
+pub const MAX_CHORD: usize = 6;
+/// Maximum amount of note events produced by a single input.
+const MAX_OUTPUT: usize = MAX_HELD * MAX_CHORD;
+
+/// Note event going into or out of the performance modes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NoteEvent {
+    /// MIDI channel (0-15).
+    pub channel: u8,
+    /// MIDI note number.
+    pub note: u8,
+    pub velocity: u8,
+    /// Note-On if true, Note-Off otherwise.
+    pub on: bool,
+}
+
+/// Note events to be sent.
+pub type Output = heapless::Vec<NoteEvent, MAX_OUTPUT>;
+
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum PerformMode {
+    /// Notes are sent as played.
+    Off,
+    /// Notes keep sounding after release, until the next chord is played.
+    Latch,
+    /// Every key plays the stored chord, transposed to that key.
+    Chord,
+    /// Held notes are played one at a time, following the clock.
+    Arp,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum ArpPattern {
+    /// Lowest to highest.
+    Up,
+    /// Highest to lowest.
+    Down,
+    /// Lowest to highest and back, without repeating the ends.
+    UpDown,
+    /// Random held note every step.
+    Random,
+    /// Order the keys were pressed in.
+    Order,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ArpConfig {
+    pub pattern: ArpPattern,
+    /// Amount of octaves the pattern spans (1 only plays the held notes).
+    pub octaves: u8,
+    /// Clock ticks (24 per quarter note) per step. For example, 6 plays sixteenth notes.
+    pub rate: u8,
+    /// Percentage of a step that each note sounds for.
+    pub gate: u8,
+    /// Keep arpeggiating after keys are released, until the next chord is played.
+    pub latch: bool,
+}
+
+impl ArpConfig {
+    /// Sixteenth notes going up over one octave.
+    pub const DEFAULT: ArpConfig = ArpConfig {
+        pattern: ArpPattern::Up,
+        octaves: 1,
+        rate: 6,
+        gate: 50,
+        latch: false,
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Held {
+    channel: u8,
+    note: u8,
+    velocity: u8,
+}
+
+impl Held {
+    fn event(&self, note: u8, on: bool) -> NoteEvent {
+        NoteEvent {
+            channel: self.channel,
+            note,
+            velocity: if on { self.velocity } else { 0 },
+            on,
+        }
+    }
+}
+
+/// Performance mode state machine.
+pub struct Performer {
+    mode: PerformMode,
+    pub arp: ArpConfig,
+    /// Keys physically held down, in the order they were pressed.
+    pressed: heapless::Vec<Held, MAX_HELD>,
+    /// Notes held by the latch, or notes being arpeggiated.
+    latched: heapless::Vec<Held, MAX_HELD>,
+    /// Stored chord, as intervals from the key played.
+    chord: heapless::Vec<i8, MAX_CHORD>,
+    /// Next chord played is stored in `chord`.
+    learning: bool,
+    /// Notes played while learning a chord.
+    learned: heapless::Vec<u8, MAX_CHORD>,
+    /// Clock ticks since the start of the current arpeggio step.
+    tick: u32,
+    /// Arpeggio step counter.
+    step: usize,
+    /// Note currently played by the arpeggiator.
+    arp_note: Option<(Held, u8)>,
+    /// Random number generator state.
+    rng: u32,
+}
+
+/// Push an event, ignoring it if the output is full.
+///
+/// The output holds a chord for every held key, so this can't happen in practice.
+fn push(out: &mut Output, ev: NoteEvent) {
+    let _ = out.push(ev);
+}
+
+impl Performer {
+    pub const fn new(arp: ArpConfig) -> Self {
+        Performer {
+            mode: PerformMode::Off,
+            arp,
+            pressed: heapless::Vec::new(),
+            latched: heapless::Vec::new(),
+            chord: heapless::Vec::new(),
+            learning: false,
+            learned: heapless::Vec::new(),
+            tick: 0,
+            step: 0,
+            arp_note: None,
+            rng: 0x2545_f491,
+        }
+    }
+
+    pub fn mode(&self) -> PerformMode {
+        self.mode
+    }
+
+    /// The next chord played will be stored.
+    pub fn is_learning(&self) -> bool {
+        self.learning
+    }
+
+    /// Stored chord, as intervals from its lowest note.
+    pub fn chord(&self) -> &[i8] {
+        &self.chord
+    }
+
+    /// Change the mode. Returns Note-Offs for anything the old mode left sounding.
+    pub fn set_mode(&mut self, mode: PerformMode) -> Output {
+        let out = self.all_off();
+        self.mode = mode;
+        self.learning = false;
+        out
+    }
+
+    /// Switch to chord mode, and store the next chord played.
+    ///
+    /// The chord is complete once all its keys are released. Its lowest note is played by the
+    /// key pressed afterwards.
+    pub fn learn_chord(&mut self) -> Output {
+        let out = self.set_mode(PerformMode::Chord);
+        self.learning = true;
+        self.learned.clear();
+        out
+    }
+
+    /// Notes of the stored chord for a key.
+    fn chord_notes(&self, note: u8) -> impl Iterator<Item = u8> + '_ {
+        self.chord.iter().filter_map(move |interval| {
+            let n = note as i16 + *interval as i16;
+            (0..=127).contains(&n).then_some(n as u8)
+        })
+    }
+
+    /// Release everything sounding because of the current mode.
+    fn all_off(&mut self) -> Output {
+        let mut out = Output::new();
+        match self.mode {
+            PerformMode::Off => {}
+            PerformMode::Latch => {
+                for held in self.latched.iter() {
+                    push(&mut out, held.event(held.note, false));
+                }
+            }
+            PerformMode::Chord => {
+                for held in self.pressed.iter() {
+                    if self.chord.is_empty() || self.learning {
+                        push(&mut out, held.event(held.note, false));
+                    } else {
+                        for note in self.chord_notes(held.note) {
+                            push(&mut out, held.event(note, false));
+                        }
+                    }
+                }
+            }
+            PerformMode::Arp => self.stop_arp_note(&mut out),
+        }
+        self.latched.clear();
+        out
+    }
+
+    /// Handle a Note-On.
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: u8) -> Output {
+        let mut out = Output::new();
+        let held = Held {
+            channel,
+            note,
+            velocity,
+        };
+        // first key of a new chord
+        let new_chord = self.pressed.is_empty();
+        if self.pressed.push(held).is_err() {
+            // too many keys held
+            return out;
+        }
+
+        match self.mode {
+            PerformMode::Off => push(&mut out, held.event(note, true)),
+            PerformMode::Latch => {
+                if new_chord {
+                    for old in self.latched.iter() {
+                        push(&mut out, old.event(old.note, false));
+                    }
+                    self.latched.clear();
+                }
+                if let Some(i) = self
+                    .latched
+                    .iter()
+                    .position(|h| h.note == note && h.channel == channel)
+                {
+                    // same note played twice in a chord
+                    self.latched.swap_remove(i);
+                    push(&mut out, held.event(note, false));
+                }
+                let _ = self.latched.push(held);
+                push(&mut out, held.event(note, true));
+            }
+            PerformMode::Chord => {
+                if self.learning {
+                    let _ = self.learned.push(note);
+                    push(&mut out, held.event(note, true));
+                } else if self.chord.is_empty() {
+                    push(&mut out, held.event(note, true));
+                } else {
+                    for n in self.chord_notes(note) {
+                        push(&mut out, held.event(n, true));
+                    }
+                }
+            }
+            PerformMode::Arp => {
+                if new_chord && self.arp.latch {
+                    self.latched.clear();
+                }
+                if self.latched.is_empty() {
+                    // start the pattern from the beginning on the next tick
+                    self.tick = 0;
+                    self.step = 0;
+                }
+                if !self
+                    .latched
+                    .iter()
+                    .any(|h| h.note == note && h.channel == channel)
+                {
+                    let _ = self.latched.push(held);
+                }
+            }
+        }
+        out
+    }
+
+    /// Handle a Note-Off.
+    pub fn note_off(&mut self, channel: u8, note: u8) -> Output {
+        let mut out = Output::new();
+        let Some(i) = self
+            .pressed
+            .iter()
+            .position(|h| h.note == note && h.channel == channel)
+        else {
+            // e.g. the key was pressed before a mode change
+            push(
+                &mut out,
+                NoteEvent {
+                    channel,
+                    note,
+                    velocity: 0,
+                    on: false,
+                },
+            );
+            return out;
+        };
+        let held = self.pressed.remove(i);
+
+        match self.mode {
+            PerformMode::Off => push(&mut out, held.event(note, false)),
+            PerformMode::Latch => {}
+            PerformMode::Chord => {
+                if self.learning {
+                    push(&mut out, held.event(note, false));
+                    if self.pressed.is_empty() && !self.learned.is_empty() {
+                        self.learned.sort_unstable();
+                        let root = self.learned[0];
+                        self.chord.clear();
+                        for n in self.learned.iter() {
+                            let _ = self.chord.push((*n - root) as i8);
+                        }
+                        self.learning = false;
+                    }
+                } else if self.chord.is_empty() {
+                    push(&mut out, held.event(note, false));
+                } else {
+                    for n in self.chord_notes(note) {
+                        push(&mut out, held.event(n, false));
+                    }
+                }
+            }
+            PerformMode::Arp => {
+                if !self.arp.latch {
+                    self.latched
+                        .retain(|h| !(h.note == note && h.channel == channel));
+                }
+                if self.latched.is_empty() {
+                    self.stop_arp_note(&mut out);
+                }
+            }
+        }
+        out
+    }
+
+    fn stop_arp_note(&mut self, out: &mut Output) {
+        if let Some((held, note)) = self.arp_note.take() {
+            push(out, held.event(note, false));
+        }
+    }
+
+    fn random(&mut self) -> u32 {
+        // xorshift32
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+
+    /// Amount of notes in the full pattern of ascending notes over all octaves.
+    fn arp_span(&self) -> usize {
+        self.latched.len() * max(self.arp.octaves, 1) as usize
+    }
+
+    /// Note at some position in the pattern of ascending notes over all octaves.
+    fn arp_note_at(&self, idx: usize) -> (Held, u8) {
+        let mut notes = self.latched.clone();
+        if self.arp.pattern != ArpPattern::Order {
+            notes.sort_unstable_by_key(|h| h.note);
+        }
+        let held = notes[idx % notes.len()];
+        let mut note = held.note as usize + 12 * (idx / notes.len());
+        while note > 127 {
+            note -= 12;
+        }
+        (held, note as u8)
+    }
+
+    /// Pick the next note of the arpeggio.
+    fn next_arp_note(&mut self) -> Option<(Held, u8)> {
+        let span = self.arp_span();
+        if span == 0 {
+            return None;
+        }
+        let step = self.step;
+        self.step = self.step.wrapping_add(1);
+        let idx = match self.arp.pattern {
+            ArpPattern::Up | ArpPattern::Order => step % span,
+            ArpPattern::Down => span - 1 - step % span,
+            ArpPattern::UpDown => {
+                let period = max(2 * span - 2, 1);
+                let i = step % period;
+                if i < span {
+                    i
+                } else {
+                    period - i
+                }
+            }
+            ArpPattern::Random => self.random() as usize % span,
+        };
+        Some(self.arp_note_at(idx))
+    }
+
+    /// Advance by one clock tick (24 per quarter note).
+    pub fn tick(&mut self) -> Output {
+        let mut out = Output::new();
+        if self.mode != PerformMode::Arp {
+            return out;
+        }
+        let rate = max(self.arp.rate, 1) as u32;
+        let gate_ticks = max(rate * self.arp.gate as u32 / 100, 1);
+
+        if self.tick == 0 {
+            self.stop_arp_note(&mut out);
+            if let Some((held, note)) = self.next_arp_note() {
+                push(&mut out, held.event(note, true));
+                self.arp_note = Some((held, note));
+            }
+        } else if self.tick >= gate_ticks {
+            self.stop_arp_note(&mut out);
+        }
+        self.tick = (self.tick + 1) % rate;
+        out
+    }
+
+    /// Go back to the start of the pattern (e.g. on MIDI Start).
+    pub fn restart(&mut self) -> Output {
+        let mut out = Output::new();
+        self.stop_arp_note(&mut out);
+        self.tick = 0;
+        self.step = 0;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Notes in the output, as (note, on).
+    fn notes(out: Output) -> heapless::Vec<(u8, bool), MAX_OUTPUT> {
+        out.iter().map(|ev| (ev.note, ev.on)).collect()
+    }
+
+    fn arp(pattern: ArpPattern, octaves: u8) -> Performer {
+        let mut p = Performer::new(ArpConfig {
+            pattern,
+            octaves,
+            rate: 1,
+            gate: 50,
+            latch: false,
+        });
+        p.set_mode(PerformMode::Arp);
+        p
+    }
+
+    /// Notes started by the arpeggiator over some amount of clock ticks (one step each).
+    fn arp_steps(p: &mut Performer, steps: usize) -> heapless::Vec<u8, 32> {
+        (0..steps)
+            .flat_map(|_| p.tick())
+            .filter(|ev| ev.on)
+            .map(|ev| ev.note)
+            .collect()
+    }
+
+    #[test]
+    fn off_passes_notes_through() {
+        let mut p = Performer::new(ArpConfig::DEFAULT);
+        assert_eq!(notes(p.note_on(0, 60, 100)), [(60, true)]);
+        assert_eq!(notes(p.note_off(0, 60)), [(60, false)]);
+    }
+
+    #[test]
+    fn latch_on() {
+        let mut p = Performer::new(ArpConfig::DEFAULT);
+        p.set_mode(PerformMode::Latch);
+        assert_eq!(notes(p.note_on(0, 60, 100)), [(60, true)]);
+        assert_eq!(notes(p.note_on(0, 64, 100)), [(64, true)]);
+        // released keys keep sounding
+        assert!(p.note_off(0, 60).is_empty());
+        assert!(p.note_off(0, 64).is_empty());
+        // the next chord replaces them
+        assert_eq!(
+            notes(p.note_on(0, 67, 100)),
+            [(60, false), (64, false), (67, true)]
+        );
+    }
+
+    #[test]
+    fn latch_off() {
+        let mut p = Performer::new(ArpConfig::DEFAULT);
+        p.set_mode(PerformMode::Latch);
+        p.note_on(0, 60, 100);
+        p.note_off(0, 60);
+        assert_eq!(notes(p.set_mode(PerformMode::Off)), [(60, false)]);
+        assert_eq!(notes(p.note_on(0, 62, 100)), [(62, true)]);
+        assert_eq!(notes(p.note_off(0, 62)), [(62, false)]);
+    }
+
+    #[test]
+    fn chord_learn_and_replay() {
+        let mut p = Performer::new(ArpConfig::DEFAULT);
+        p.learn_chord();
+        assert!(p.is_learning());
+        // learned notes play as-is, in any order
+        assert_eq!(notes(p.note_on(0, 67, 100)), [(67, true)]);
+        assert_eq!(notes(p.note_on(0, 60, 100)), [(60, true)]);
+        assert_eq!(notes(p.note_on(0, 64, 100)), [(64, true)]);
+        p.note_off(0, 60);
+        p.note_off(0, 64);
+        assert!(p.is_learning());
+        assert_eq!(notes(p.note_off(0, 67)), [(67, false)]);
+        assert!(!p.is_learning());
+        assert_eq!(p.chord(), [0, 4, 7]);
+
+        // played from another key, transposed
+        assert_eq!(
+            notes(p.note_on(0, 62, 90)),
+            [(62, true), (66, true), (69, true)]
+        );
+        assert_eq!(
+            notes(p.note_off(0, 62)),
+            [(62, false), (66, false), (69, false)]
+        );
+    }
+
+    #[test]
+    fn chord_drops_notes_out_of_range() {
+        let mut p = Performer::new(ArpConfig::DEFAULT);
+        p.learn_chord();
+        p.note_on(0, 60, 100);
+        p.note_on(0, 72, 100);
+        p.note_off(0, 60);
+        p.note_off(0, 72);
+        assert_eq!(notes(p.note_on(0, 120, 100)), [(120, true)]);
+    }
+
+    #[test]
+    fn arp_up() {
+        let mut p = arp(ArpPattern::Up, 1);
+        p.note_on(0, 64, 100);
+        p.note_on(0, 60, 100);
+        p.note_on(0, 67, 100);
+        assert_eq!(arp_steps(&mut p, 4), [60, 64, 67, 60]);
+    }
+
+    #[test]
+    fn arp_down() {
+        let mut p = arp(ArpPattern::Down, 1);
+        p.note_on(0, 64, 100);
+        p.note_on(0, 60, 100);
+        p.note_on(0, 67, 100);
+        assert_eq!(arp_steps(&mut p, 4), [67, 64, 60, 67]);
+    }
+
+    #[test]
+    fn arp_up_down_does_not_repeat_ends() {
+        let mut p = arp(ArpPattern::UpDown, 1);
+        p.note_on(0, 60, 100);
+        p.note_on(0, 64, 100);
+        p.note_on(0, 67, 100);
+        assert_eq!(arp_steps(&mut p, 7), [60, 64, 67, 64, 60, 64, 67]);
+
+        // a single note still plays every step
+        let mut p = arp(ArpPattern::UpDown, 1);
+        p.note_on(0, 60, 100);
+        assert_eq!(arp_steps(&mut p, 3), [60, 60, 60]);
+    }
+
+    #[test]
+    fn arp_order() {
+        let mut p = arp(ArpPattern::Order, 1);
+        p.note_on(0, 67, 100);
+        p.note_on(0, 60, 100);
+        p.note_on(0, 64, 100);
+        assert_eq!(arp_steps(&mut p, 3), [67, 60, 64]);
+    }
+
+    #[test]
+    fn arp_random_plays_held_notes() {
+        let mut p = arp(ArpPattern::Random, 1);
+        p.note_on(0, 60, 100);
+        p.note_on(0, 64, 100);
+        for note in arp_steps(&mut p, 32) {
+            assert!(note == 60 || note == 64);
+        }
+    }
+
+    #[test]
+    fn arp_octaves_wrap() {
+        let mut p = arp(ArpPattern::Up, 2);
+        p.note_on(0, 60, 100);
+        p.note_on(0, 64, 100);
+        assert_eq!(arp_steps(&mut p, 5), [60, 64, 72, 76, 60]);
+
+        // notes past the top of the MIDI range come back down an octave
+        let mut p = arp(ArpPattern::Up, 2);
+        p.note_on(0, 120, 100);
+        assert_eq!(arp_steps(&mut p, 2), [120, 120]);
+    }
+
+    #[test]
+    fn arp_gate() {
+        let mut p = Performer::new(ArpConfig {
+            rate: 4,
+            gate: 50,
+            ..ArpConfig::DEFAULT
+        });
+        p.set_mode(PerformMode::Arp);
+        p.note_on(0, 60, 100);
+        assert_eq!(notes(p.tick()), [(60, true)]);
+        assert!(p.tick().is_empty());
+        assert_eq!(notes(p.tick()), [(60, false)]);
+        assert!(p.tick().is_empty());
+        assert_eq!(notes(p.tick()), [(60, true)]);
+    }
+
+    #[test]
+    fn arp_stops_on_last_release() {
+        let mut p = Performer::new(ArpConfig {
+            rate: 4,
+            gate: 100,
+            ..ArpConfig::DEFAULT
+        });
+        p.set_mode(PerformMode::Arp);
+        p.note_on(0, 60, 100);
+        p.note_on(0, 64, 100);
+        assert_eq!(notes(p.tick()), [(60, true)]);
+        // the note being played keeps going while another key is held
+        assert!(p.note_off(0, 64).is_empty());
+        // the last key stops it right away
+        assert_eq!(notes(p.note_off(0, 60)), [(60, false)]);
+        for _ in 0..8 {
+            assert!(p.tick().is_empty());
+        }
+    }
+
+    #[test]
+    fn arp_latch_keeps_going_after_release() {
+        let mut p = Performer::new(ArpConfig {
+            rate: 1,
+            latch: true,
+            ..ArpConfig::DEFAULT
+        });
+        p.set_mode(PerformMode::Arp);
+        p.note_on(0, 60, 100);
+        p.note_on(0, 64, 100);
+        assert!(p.note_off(0, 60).is_empty());
+        assert!(p.note_off(0, 64).is_empty());
+        assert_eq!(arp_steps(&mut p, 2), [60, 64]);
+        // the next chord replaces the latched one
+        p.note_on(0, 67, 100);
+        assert_eq!(arp_steps(&mut p, 2), [67, 67]);
+    }
+
+    #[test]
+    fn mode_change_releases_notes() {
+        let mut p = Performer::new(ArpConfig::DEFAULT);
+        p.set_mode(PerformMode::Chord);
+        p.note_on(0, 60, 100);
+        assert_eq!(notes(p.set_mode(PerformMode::Off)), [(60, false)]);
+        // the key was pressed in the old mode; its release still gets through
+        assert_eq!(notes(p.note_off(0, 60)), [(60, false)]);
+    }
+}
@@ -0,0 +1,274 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Buses the extenders can be wired to: I²C (any supported chip) or SPI (MCP23S17).
+//!
+//! The MCP23S17 has the same registers as the MCP23017, so the SPI bus is made to look like an
+//! I²C bus, and the same driver is used for both. Extender addresses are the same for both chips
+//! (0x20 to 0x27, depending on the A0-A2 pins).
+//!
+//! Both buses are async, so that other tasks can run during transfers.
+
+use embassy_rp::{
+    gpio::{AnyPin, Output},
+    i2c::{self, Async},
+    pac,
+    peripherals::{I2C0, SPI0},
+    spi,
+};
+use embassy_time::{block_for, Duration};
+use embedded_hal_async::i2c::{ErrorKind, ErrorType, I2c, Operation};
+
+pub type I2cPeripheral = i2c::I2c<'static, I2C0, Async>;
+pub type SpiPeripheral = spi::Spi<'static, SPI0, spi::Async>;
+
+/// MCP23S17 control byte, without the address and read bit.
+const OPCODE: u8 = 0x40;
+/// Read bit of the control byte.
+const OPCODE_READ: u8 = 0x01;
+/// IOCON register (in bank 0 mode).
+const IOCON: u8 = 0x0a;
+/// IOCON bit that makes the chips use their address pins.
+const IOCON_HAEN: u8 = 1 << 3;
+
+/// GPIO function of the I²C peripheral.
+const FUNCSEL_I2C: u8 = 3;
+/// GPIO function of software-controlled pins.
+const FUNCSEL_SIO: u8 = 5;
+/// Most SCL pulses needed for a device to let go of SDA (a byte and its acknowledge).
+const RECOVERY_PULSES: usize = 9;
+/// Half of an SCL period during recovery (100 kHz).
+const RECOVERY_HALF_PERIOD_US: u64 = 5;
+
+/// MCP23S17 chips sharing an SPI bus and a chip-select pin, behaving like an I²C bus.
+///
+/// The SPI clock can go up to 10 MHz.
+pub struct Mcp23s17Bus {
+    spi: SpiPeripheral,
+    cs: Output<'static, AnyPin>,
+}
+
+impl Mcp23s17Bus {
+    /// Set up the chips so they can be told apart by address.
+    pub async fn new(spi: SpiPeripheral, cs: Output<'static, AnyPin>) -> Result<Self, spi::Error> {
+        let mut ret = Mcp23s17Bus { spi, cs };
+        ret.enable_addressing().await?;
+        Ok(ret)
+    }
+
+    /// Turn hardware addressing on in all the chips. This is lost when a chip is reset.
+    async fn enable_addressing(&mut self) -> Result<(), spi::Error> {
+        // With hardware addressing off, every chip answers to address 0, so this reaches them
+        // all. Some chips with A2 high only answer to address 4 (see the errata), so do both.
+        for addr in [0x20, 0x24] {
+            self.transaction(addr, &mut [Operation::Write(&[IOCON, IOCON_HAEN])])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Run I²C-style operations on the chip at an address: writes go to the registers, and reads
+    /// come from them.
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), spi::Error> {
+        let mut opcode = OPCODE | ((address & 0x07) << 1);
+        if operations.iter().any(|op| matches!(op, Operation::Read(_))) {
+            opcode |= OPCODE_READ;
+        }
+        self.cs.set_low();
+        let mut ret = self.spi.write(&[opcode]).await;
+        for op in operations.iter_mut() {
+            if ret.is_err() {
+                break;
+            }
+            ret = match op {
+                Operation::Write(bytes) => self.spi.write(bytes).await,
+                Operation::Read(buf) => self.spi.read(buf).await,
+            };
+        }
+        self.cs.set_high();
+        ret
+    }
+}
+
+/// Bus the extenders are on.
+pub enum Bus {
+    /// Extenders over I²C, with the GPIO numbers of SCL and SDA if the bus can be recovered.
+    I2c(I2cPeripheral, Option<(u8, u8)>),
+    /// MCP23S17 chips over SPI.
+    Spi(Mcp23s17Bus),
+}
+
+impl From<I2cPeripheral> for Bus {
+    fn from(i2c: I2cPeripheral) -> Self {
+        Bus::I2c(i2c, None)
+    }
+}
+
+impl From<Mcp23s17Bus> for Bus {
+    fn from(spi: Mcp23s17Bus) -> Self {
+        Bus::Spi(spi)
+    }
+}
+
+impl Bus {
+    /// I²C bus that can be recovered after errors (see [`Bus::recover`]), given the GPIO numbers
+    /// of the pins used for SCL and SDA.
+    pub fn i2c_with_recovery(i2c: I2cPeripheral, scl: u8, sda: u8) -> Self {
+        Bus::I2c(i2c, Some((scl, sda)))
+    }
+
+    /// Try to get the bus working again after an error.
+    ///
+    /// On I²C, a device can be left holding SDA low in the middle of a byte (e.g. after a glitch
+    /// on SCL, or a reset of the Pico), which blocks the whole bus. SCL is then clocked until SDA
+    /// is let go, and a STOP is sent. On SPI, hardware addressing is turned on again, in case a
+    /// chip was reset.
+    pub async fn recover(&mut self) {
+        match self {
+            Bus::I2c(_, Some((scl, sda))) => clock_out(*scl, *sda),
+            Bus::I2c(_, None) => {}
+            Bus::Spi(spi) => {
+                if let Err(err) = spi.enable_addressing().await {
+                    defmt::warn!("recover: could not set up MCP23S17 addressing: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Clock SCL until SDA is let go, then send a STOP, taking the pins from the I²C peripheral for
+/// a moment. Both lines are driven open-drain, relying on the bus pull-ups.
+fn clock_out(scl: u8, sda: u8) {
+    let funcsel = |pin: u8, func: u8| {
+        pac::IO_BANK0
+            .gpio(pin as usize)
+            .ctrl()
+            .write(|w| w.set_funcsel(func))
+    };
+    let drive_low = |pin: u8| pac::SIO.gpio_oe(0).value_set().write_value(1 << pin);
+    let release = |pin: u8| pac::SIO.gpio_oe(0).value_clr().write_value(1 << pin);
+    let sda_high = || pac::SIO.gpio_in(0).read() & (1 << sda) != 0;
+    let delay = || block_for(Duration::from_micros(RECOVERY_HALF_PERIOD_US));
+
+    release(scl);
+    release(sda);
+    pac::SIO
+        .gpio_out(0)
+        .value_clr()
+        .write_value((1 << scl) | (1 << sda));
+    funcsel(scl, FUNCSEL_SIO);
+    funcsel(sda, FUNCSEL_SIO);
+
+    let mut pulses = 0;
+    while pulses < RECOVERY_PULSES && !sda_high() {
+        drive_low(scl);
+        delay();
+        release(scl);
+        delay();
+        pulses += 1;
+    }
+    // START then STOP, which resets every device's state machine
+    drive_low(sda);
+    delay();
+    release(sda);
+    delay();
+    defmt::info!("recover: clocked out I2C bus after {} pulses", pulses);
+
+    funcsel(scl, FUNCSEL_I2C);
+    funcsel(sda, FUNCSEL_I2C);
+}
+
+#[derive(Debug, defmt::Format)]
+pub enum BusError {
+    I2c(i2c::Error),
+    Spi(spi::Error),
+}
+
+impl embedded_hal_async::i2c::Error for BusError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            BusError::I2c(err) => err.kind(),
+            BusError::Spi(_) => ErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorType for Bus {
+    type Error = BusError;
+}
+
+impl I2c for Bus {
+    async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        match self {
+            Bus::I2c(i2c, _) => i2c.read(address, read).await.map_err(BusError::I2c),
+            Bus::Spi(_) => {
+                self.transaction(address, &mut [Operation::Read(read)])
+                    .await
+            }
+        }
+    }
+
+    async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            Bus::I2c(i2c, _) => i2c.write(address, write).await.map_err(BusError::I2c),
+            Bus::Spi(_) => {
+                self.transaction(address, &mut [Operation::Write(write)])
+                    .await
+            }
+        }
+    }
+
+    async fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        match self {
+            Bus::I2c(i2c, _) => i2c
+                .write_read(address, write, read)
+                .await
+                .map_err(BusError::I2c),
+            Bus::Spi(_) => {
+                let mut ops = [Operation::Write(write), Operation::Read(read)];
+                self.transaction(address, &mut ops).await
+            }
+        }
+    }
+
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        match self {
+            Bus::I2c(i2c, _) => i2c
+                .transaction(address, operations)
+                .await
+                .map_err(BusError::I2c),
+            Bus::Spi(spi) => spi
+                .transaction(address, operations)
+                .await
+                .map_err(BusError::Spi),
+        }
+    }
+}
@@ -0,0 +1,415 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Supported I/O extender chips, all with 16 pins.
+//!
+//! - MCP23017 (I²C), or MCP23S17 (SPI, see [`super::Mcp23s17Bus`]). Pull-ups can be turned on
+//!   and off for each pin.
+//! - PCA9555, TCA9555 and TCA9535 (I²C). Pull-ups can't be changed: the PCA9555 always has them,
+//!   and the TCA chips have none, so inputs need external pull-up resistors. These chips can
+//!   invert the polarity of inputs; this is turned off at startup so that reads mean the same
+//!   thing on every chip.
+//! - PCF8575 (I²C). The pins are quasi-bidirectional: there are no registers, and a pin written
+//!   high is an input with a weak pull-up, while a pin written low is a (strong) output. Outputs
+//!   can only be driven high weakly.
+//!
+//! Within an extender, pins 0-7 are port A (or port 0), and pins 8-15 are port B (or port 1).
+
+use super::{Bus, BusError, MAX_EXTENDERS};
+use embassy_rp::gpio::Pull;
+use embedded_hal_async::i2c::I2c;
+use mcp23017::MCP23017;
+
+/// MCP23017 configuration register (port A, in bank 0 mode).
+const MCP_IOCON: u8 = 0x0a;
+/// Interrupt mirroring bit in `MCP_IOCON`, which is set up by [`Extender::init`] and is off after
+/// a reset.
+const MCP_IOCON_MIRROR: u8 = 1 << 6;
+/// Register written to check that a MCP23017 or PCA9555 is there. It is GPINTEN on the MCP23017
+/// and the polarity inversion on the PCA9555, which are both harmless and cleared after.
+const PROBE_REG: u8 = 0x04;
+/// Test pattern written when probing.
+const PROBE_PATTERN: [u8; 2] = [0xa5, 0x5a];
+/// First extender address. The chips have three address pins, so there can be up to
+/// [`MAX_EXTENDERS`] from there.
+const FIRST_ADDR: u8 = 0x20;
+/// PCA9555 input port register (port 0, then port 1).
+const PCA_INPUT: u8 = 0x00;
+/// PCA9555 output port register.
+const PCA_OUTPUT: u8 = 0x02;
+/// PCA9555 polarity inversion register.
+const PCA_POLARITY: u8 = 0x04;
+/// PCA9555 configuration register (1 bit is input).
+const PCA_CONFIG: u8 = 0x06;
+
+/// Kind of extender chip.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Chip {
+    /// MCP23017 or MCP23S17.
+    Mcp23017,
+    /// PCA9555, TCA9555 or TCA9535.
+    Pca9555,
+    /// PCF8575.
+    Pcf8575,
+}
+
+/// Extender chip on the bus.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct Extender {
+    pub chip: Chip,
+    /// Address on the bus, e.g. 0x20.
+    pub addr: u8,
+}
+
+/// Registers that are cached.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Register {
+    /// Pin modes (1 bit is input).
+    Dir,
+    /// Pull-ups (1 bit is on).
+    Pull,
+    /// Output levels (1 bit is high).
+    Out,
+}
+
+/// Values last written to an extender's registers, so that writes that change nothing can be
+/// skipped. `None` if unknown.
+#[derive(Clone, Copy, Default)]
+pub(super) struct Written {
+    dir: Option<u16>,
+    pull: Option<u16>,
+    /// For the PCF8575, this is the whole port, inputs included.
+    out: Option<u16>,
+}
+
+/// Operation on an extender, to tell where an error happened.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Operation {
+    /// Checking that the chip answers (see [`probe`]).
+    Probe,
+    /// Setting the chip up.
+    Init,
+    /// Writing a register.
+    Write(Register),
+    /// Reading the pins.
+    Read,
+    /// Setting up interrupts.
+    Interrupts,
+    /// Reading registers back, to check that the chip wasn't reset.
+    CheckReset,
+}
+
+impl Operation {
+    /// Attach this operation to a bus error.
+    fn failed(self) -> impl Fn(BusError) -> Failure + Copy {
+        move |err| Failure { op: self, err }
+    }
+}
+
+/// Bus error during an operation on an extender.
+pub(super) struct Failure {
+    pub op: Operation,
+    pub err: BusError,
+}
+
+/// Driver for a MCP23017 on the bus.
+fn mcp_driver(bus: &mut Bus, addr: u8) -> MCP23017<&mut Bus> {
+    match MCP23017::new(bus, addr) {
+        Ok(ext) => ext,
+        // this doesn't touch the bus
+        Err(_) => unreachable!(),
+    }
+}
+
+impl Written {
+    fn reg_mut(&mut self, reg: Register) -> &mut Option<u16> {
+        match reg {
+            Register::Dir => &mut self.dir,
+            Register::Pull => &mut self.pull,
+            Register::Out => &mut self.out,
+        }
+    }
+}
+
+impl Extender {
+    pub const fn mcp23017(addr: u8) -> Self {
+        Extender {
+            chip: Chip::Mcp23017,
+            addr,
+        }
+    }
+
+    pub const fn pca9555(addr: u8) -> Self {
+        Extender {
+            chip: Chip::Pca9555,
+            addr,
+        }
+    }
+
+    pub const fn pcf8575(addr: u8) -> Self {
+        Extender {
+            chip: Chip::Pcf8575,
+            addr,
+        }
+    }
+
+    /// Write one port (0 or 1) of a register.
+    async fn write_port(
+        &self,
+        bus: &mut Bus,
+        reg: Register,
+        port: u8,
+        byte: u8,
+    ) -> Result<(), BusError> {
+        // any pin on the port
+        let pin = port * 8;
+        let word = (byte as u16) << pin;
+        match (self.chip, reg) {
+            (Chip::Mcp23017, reg) => {
+                let mut ext = mcp_driver(bus, self.addr);
+                match reg {
+                    Register::Dir => ext.overwrite_pin_mode(pin, word).await?,
+                    Register::Pull => ext.overwrite_pull_up(pin, word).await?,
+                    Register::Out => {
+                        let port = if port == 0 {
+                            mcp23017::Port::GPIOA
+                        } else {
+                            mcp23017::Port::GPIOB
+                        };
+                        ext.write_gpio(port, byte).await?
+                    }
+                }
+            }
+            (Chip::Pca9555, Register::Dir) => {
+                bus.write(self.addr, &[PCA_CONFIG + port, byte]).await?
+            }
+            (Chip::Pca9555, Register::Out) => {
+                bus.write(self.addr, &[PCA_OUTPUT + port, byte]).await?
+            }
+            // pull-ups are fixed
+            (Chip::Pca9555, Register::Pull) => {}
+            // ports can't be written separately
+            (Chip::Pcf8575, _) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Write a register, only on the ports that changed since the last write.
+    async fn update(
+        &self,
+        bus: &mut Bus,
+        written: &mut Written,
+        reg: Register,
+        word: u16,
+    ) -> Result<(), Failure> {
+        let failed = Operation::Write(reg).failed();
+        let last = written.reg_mut(reg);
+        if *last == Some(word) {
+            return Ok(());
+        }
+        if self.chip == Chip::Pcf8575 {
+            let res = bus.write(self.addr, &word.to_le_bytes()).await;
+            res.map_err(failed)?;
+        } else {
+            for port in 0..2 {
+                let byte = (word >> (8 * port)) as u8;
+                if last.map(|w| (w >> (8 * port)) as u8) != Some(byte) {
+                    let res = self.write_port(bus, reg, port, byte).await;
+                    res.map_err(failed)?;
+                }
+            }
+        }
+        *last = Some(word);
+        Ok(())
+    }
+
+    /// Set the chip up, e.g. after it was reset. All registers are written on the next sync.
+    ///
+    /// On the MCP23017, this also sets up the interrupt outputs (see
+    /// [`Extender::arm_interrupt`]), which tells [`Extender::is_reset`] that the chip was set up.
+    pub(super) async fn init(&self, bus: &mut Bus, written: &mut Written) -> Result<(), Failure> {
+        *written = Written::default();
+        let failed = Operation::Init.failed();
+        match self.chip {
+            Chip::Mcp23017 => {
+                let mut ext = mcp_driver(bus, self.addr);
+                ext.setup_interrupts(true, true, mcp23017::Polarity::LOW)
+                    .await
+                    .map_err(failed)?;
+            }
+            Chip::Pca9555 => {
+                let res = bus.write(self.addr, &[PCA_POLARITY, 0, 0]).await;
+                res.map_err(failed)?;
+            }
+            Chip::Pcf8575 => {}
+        }
+        Ok(())
+    }
+
+    /// Check whether the chip lost its setup, e.g. because of a brownout.
+    ///
+    /// Comparing the pin modes isn't enough, since a chip with only inputs has the same pin modes
+    /// as after a reset. On the MCP23017, the IOCON register is checked instead, since it's never
+    /// left at its reset value. The PCA9555 has nothing else to set up, so its pin modes and
+    /// output levels are compared with what was written: if they're at their reset values, the
+    /// chip is already in the right state. The PCF8575 has no registers to read back, so this is
+    /// always false for it.
+    pub(super) async fn is_reset(&self, bus: &mut Bus, written: &Written) -> Result<bool, Failure> {
+        let failed = Operation::CheckReset.failed();
+        match self.chip {
+            Chip::Mcp23017 => {
+                let mut buf = [0u8];
+                let res = bus.write_read(self.addr, &[MCP_IOCON], &mut buf).await;
+                res.map_err(failed)?;
+                Ok(buf[0] & MCP_IOCON_MIRROR == 0)
+            }
+            Chip::Pca9555 => {
+                for (reg, val) in [(PCA_CONFIG, written.dir), (PCA_OUTPUT, written.out)] {
+                    let Some(val) = val else {
+                        continue;
+                    };
+                    let mut buf = [0u8; 2];
+                    let res = bus.write_read(self.addr, &[reg], &mut buf).await;
+                    res.map_err(failed)?;
+                    if u16::from_le_bytes(buf) != val {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Chip::Pcf8575 => Ok(false),
+        }
+    }
+
+    /// Write the pin modes (`dir`, 1 bit is input), pull-ups and output levels, skipping what
+    /// didn't change.
+    pub(super) async fn sync(
+        &self,
+        bus: &mut Bus,
+        written: &mut Written,
+        dir: u16,
+        pull: u16,
+        out: u16,
+    ) -> Result<(), Failure> {
+        match self.chip {
+            // inputs are written high
+            Chip::Pcf8575 => self.update(bus, written, Register::Out, dir | out).await,
+            _ => {
+                self.update(bus, written, Register::Out, out).await?;
+                self.update(bus, written, Register::Pull, pull).await?;
+                self.update(bus, written, Register::Dir, dir).await
+            }
+        }
+    }
+
+    /// Read the level of all pins.
+    pub(super) async fn read(&self, bus: &mut Bus) -> Result<u16, Failure> {
+        let mut buf = [0u8; 2];
+        let res = match self.chip {
+            Chip::Mcp23017 => {
+                // read api is wonky (https://github.com/lucazulian/mcp23017/issues/8)
+                // ports are flipped from what it should be
+                let val = mcp_driver(bus, self.addr).read_gpioab().await;
+                return val.map(u16::swap_bytes).map_err(Operation::Read.failed());
+            }
+            Chip::Pca9555 => bus.write_read(self.addr, &[PCA_INPUT], &mut buf).await,
+            Chip::Pcf8575 => bus.read(self.addr, &mut buf).await,
+        };
+        res.map_err(Operation::Read.failed())?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// Make the chip's interrupt output go low while any of some pins (1 bits) is low.
+    ///
+    /// The MCP23017 is set up (by [`Extender::init`]) with both INT lines mirrored and open-drain,
+    /// so that all the lines of all extenders can be wired together. The other chips always have
+    /// an open-drain interrupt output, which is asserted when any input changes, until the inputs
+    /// are read.
+    pub(super) async fn arm_interrupt(&self, bus: &mut Bus, pins: u16) -> Result<(), Failure> {
+        let failed = Operation::Interrupts.failed();
+        if self.chip == Chip::Mcp23017 {
+            let mut ext = mcp_driver(bus, self.addr);
+            ext.overwrite_interrupt_pins(pins, mcp23017::InterruptMode::FALLING)
+                .await
+                .map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    /// Check that a chip of the right kind answers at this address.
+    ///
+    /// For the MCP23017 and PCA9555, a test pattern is written to a register and read back, which
+    /// doesn't tell these two chips apart. The PCF8575 has no registers, so it only has to answer
+    /// a read. Errors mean nothing answered.
+    pub(super) async fn verify(&self, bus: &mut Bus) -> Result<bool, BusError> {
+        let mut buf = [0u8; 2];
+        if self.chip == Chip::Pcf8575 {
+            bus.read(self.addr, &mut buf).await?;
+            return Ok(true);
+        }
+        let [lo, hi] = PROBE_PATTERN;
+        bus.write(self.addr, &[PROBE_REG, lo, hi]).await?;
+        bus.write_read(self.addr, &[PROBE_REG], &mut buf).await?;
+        if buf != PROBE_PATTERN {
+            // don't write more to a chip we don't know
+            return Ok(false);
+        }
+        bus.write(self.addr, &[PROBE_REG, 0, 0]).await?;
+        Ok(true)
+    }
+
+    /// Whether the chip supports a pull setting.
+    pub(super) fn supports_pull(&self, pull: Pull) -> bool {
+        match (self.chip, pull) {
+            // Extended pins don't seem to support pull-down
+            (_, Pull::Down) => false,
+            (Chip::Mcp23017, _) => true,
+            // pull-ups are fixed (or external), and can't be turned off
+            (_, Pull::Up) => true,
+            (_, Pull::None) => false,
+        }
+    }
+}
+
+/// Find the extenders of a kind on the bus (at addresses 0x20 to 0x27), and log what was found.
+///
+/// See [`Extender::verify`] for how chips are checked. Writing to a PCF8575 changes its pins, so
+/// don't probe for other chips on a bus that has some.
+pub async fn probe(bus: &mut Bus, chip: Chip) -> heapless::Vec<Extender, MAX_EXTENDERS> {
+    let mut ret = heapless::Vec::new();
+    for addr in FIRST_ADDR..FIRST_ADDR + MAX_EXTENDERS as u8 {
+        let ext = Extender { chip, addr };
+        match ext.verify(bus).await {
+            Ok(true) => {
+                defmt::info!("probe: found {} at {:#x}", chip, addr);
+                log::info!("probe: found {:?} at {:#x}", chip, addr);
+                // there is room for every address
+                let _ = ret.push(ext);
+            }
+            Ok(false) => {
+                defmt::warn!("probe: device at {:#x} is not a {}", addr, chip);
+                log::warn!("probe: device at {:#x} is not a {:?}", addr, chip);
+            }
+            // nothing there
+            Err(_) => {}
+        }
+    }
+    defmt::info!("probe: found {} extenders", ret.len());
+    log::info!("probe: found {} extenders", ret.len());
+    ret
+}
@@ -0,0 +1,248 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Pin numbers and addresses for [`super::TransparentPins`].
+//!
+//! Each pin has a number: extender A is 0-15, extender B is 16-31, and so on, then all the onboard
+//! pins. The enabled pins are then given addresses in order of pin number, with no gaps. This
+//! doesn't depend on the hardware, so it can be tested on the host.
+
+use super::set::PinSet;
+
+/// Number of pins driven by each pin extender.
+pub const PINS_PER_EXTENDER: usize = 16;
+/// Most extender chips of one kind that can share a bus (there are three address pins).
+pub const MAX_EXTENDERS: usize = 8;
+
+/// Some amount of extenders and onboard pins can be managed together.
+pub const fn fits(n_ext: usize, n_onboard: usize) -> bool {
+    n_ext <= MAX_EXTENDERS && n_ext * PINS_PER_EXTENDER + n_onboard <= PinSet::CAPACITY
+}
+
+/// Number of a pin on an extender (by index, pin 0-15).
+pub const fn extender_pin(ext: usize, pin: u8) -> u8 {
+    (ext * PINS_PER_EXTENDER) as u8 + pin
+}
+
+/// Number of an onboard pin (by index), after the pins of `n_ext` extenders.
+pub const fn onboard_pin(n_ext: usize, idx: usize) -> u8 {
+    (n_ext * PINS_PER_EXTENDER + idx) as u8
+}
+
+/// Addresses of the enabled pins.
+pub struct AddrMap {
+    /// Pin number of each address. Only the first `len` are used.
+    pins: [u8; PinSet::CAPACITY],
+    len: usize,
+}
+
+impl AddrMap {
+    /// Give addresses to the pins in `enabled`, in order of pin number.
+    pub fn new(enabled: &PinSet) -> Self {
+        let mut pins = [0; PinSet::CAPACITY];
+        let mut len = 0;
+        for pin in enabled.iter() {
+            pins[len] = pin;
+            len += 1;
+        }
+        AddrMap { pins, len }
+    }
+
+    /// Amount of addresses. They are `0..n_usable()`.
+    pub fn n_usable(&self) -> usize {
+        self.len
+    }
+
+    /// Pin number of an address.
+    pub fn pin(&self, addr: u8) -> Option<u8> {
+        self.pins[..self.len].get(addr as usize).copied()
+    }
+
+    /// Convert a set of addresses to the set of their pin numbers. Unused addresses are dropped.
+    pub fn addrs_to_pins(&self, addrs: &PinSet) -> PinSet {
+        let mut ret = PinSet::new();
+        for addr in addrs.iter() {
+            if let Some(pin) = self.pin(addr) {
+                ret.insert(pin);
+            }
+        }
+        ret
+    }
+
+    /// Convert a set of pin numbers to the set of their addresses. Disabled pins are dropped.
+    pub fn pins_to_addrs(&self, pins: &PinSet) -> PinSet {
+        let mut ret = PinSet::new();
+        for (addr, pin) in self.pins[..self.len].iter().enumerate() {
+            ret.set(addr as u8, pins.contains(*pin));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N_EXT: usize = 2;
+    const N_ONBOARD: usize = 3;
+    const N_PINS: usize = N_EXT * PINS_PER_EXTENDER + N_ONBOARD;
+
+    fn all_pins() -> PinSet {
+        PinSet::first(N_PINS)
+    }
+
+    /// Pin number of every address.
+    fn pins(map: &AddrMap) -> heapless::Vec<u8, { PinSet::CAPACITY }> {
+        (0..map.n_usable() as u8)
+            .map(|a| map.pin(a).unwrap())
+            .collect()
+    }
+
+    /// Check that addresses are given without gaps to all pins except `disabled`.
+    fn check_without(disabled: &[u8]) {
+        let mut enabled = all_pins();
+        for pin in disabled {
+            enabled.remove(*pin);
+        }
+        let map = AddrMap::new(&enabled);
+        let expected: heapless::Vec<u8, { PinSet::CAPACITY }> = (0..N_PINS as u8)
+            .filter(|pin| !disabled.contains(pin))
+            .collect();
+        assert_eq!(pins(&map), expected);
+        assert_eq!(map.pin(expected.len() as u8), None);
+    }
+
+    #[test]
+    fn pin_numbers() {
+        assert_eq!(extender_pin(0, 0), 0);
+        assert_eq!(extender_pin(0, 15), 15);
+        assert_eq!(extender_pin(1, 8), 24);
+        assert_eq!(onboard_pin(N_EXT, 0), 32);
+        assert_eq!(onboard_pin(N_EXT, 2), 34);
+        assert_eq!(onboard_pin(0, 5), 5);
+    }
+
+    #[test]
+    fn empty_mask() {
+        let map = AddrMap::new(&PinSet::new());
+        assert_eq!(map.n_usable(), 0);
+        assert_eq!(map.pin(0), None);
+        assert!(map.pins_to_addrs(&all_pins()).is_empty());
+        assert!(map.addrs_to_pins(&all_pins()).is_empty());
+    }
+
+    #[test]
+    fn full_mask() {
+        let map = AddrMap::new(&all_pins());
+        assert_eq!(map.n_usable(), N_PINS);
+        for addr in 0..N_PINS as u8 {
+            assert_eq!(map.pin(addr), Some(addr));
+        }
+        assert_eq!(map.pin(N_PINS as u8), None);
+        assert_eq!(map.pins_to_addrs(&all_pins()), all_pins());
+
+        // as many pins as fit
+        let map = AddrMap::new(&PinSet::first(PinSet::CAPACITY));
+        assert_eq!(map.n_usable(), PinSet::CAPACITY);
+        assert_eq!(
+            map.pin(PinSet::CAPACITY as u8 - 1),
+            Some(PinSet::CAPACITY as u8 - 1)
+        );
+    }
+
+    #[test]
+    fn single_disabled_pin_per_port() {
+        for ext in 0..N_EXT {
+            for pin in 0..PINS_PER_EXTENDER as u8 {
+                check_without(&[extender_pin(ext, pin)]);
+            }
+        }
+    }
+
+    #[test]
+    fn single_disabled_onboard_pin() {
+        for idx in 0..N_ONBOARD {
+            check_without(&[onboard_pin(N_EXT, idx)]);
+        }
+    }
+
+    #[test]
+    fn disabled_onboard_and_extender_pins() {
+        // GPA7 and GPB7 on both extenders, and an onboard pin
+        let disabled = [
+            extender_pin(0, 7),
+            extender_pin(0, 15),
+            extender_pin(1, 7),
+            extender_pin(1, 15),
+            onboard_pin(N_EXT, 1),
+        ];
+        check_without(&disabled);
+
+        let mut enabled = all_pins();
+        for pin in disabled {
+            enabled.remove(pin);
+        }
+        let map = AddrMap::new(&enabled);
+        assert_eq!(map.n_usable(), N_PINS - disabled.len());
+        assert_eq!(map.pin(7), Some(8));
+        assert_eq!(map.pin(14), Some(16));
+        assert_eq!(map.pin(21), Some(24));
+        assert_eq!(map.pin(28), Some(32));
+        assert_eq!(map.pin(29), Some(34));
+        assert_eq!(map.pin(30), None);
+    }
+
+    #[test]
+    fn conversions_drop_unused() {
+        let mut enabled = all_pins();
+        enabled.remove(3);
+        enabled.remove(20);
+        let map = AddrMap::new(&enabled);
+
+        // every pin, including disabled ones
+        let addrs = map.pins_to_addrs(&all_pins());
+        assert_eq!(addrs, PinSet::first(N_PINS - 2));
+        assert_eq!(map.addrs_to_pins(&addrs), enabled);
+
+        // addresses past the last one are dropped
+        let mut addrs = PinSet::new();
+        addrs.insert(0);
+        addrs.insert(3);
+        addrs.insert(N_PINS as u8 - 2);
+        let mut expected = PinSet::new();
+        expected.insert(0);
+        expected.insert(4);
+        assert_eq!(map.addrs_to_pins(&addrs), expected);
+    }
+
+    #[test]
+    fn size_limits() {
+        assert!(fits(0, 0));
+        assert!(fits(
+            MAX_EXTENDERS,
+            PinSet::CAPACITY - MAX_EXTENDERS * PINS_PER_EXTENDER
+        ));
+        assert!(!fits(
+            MAX_EXTENDERS,
+            PinSet::CAPACITY - MAX_EXTENDERS * PINS_PER_EXTENDER + 1
+        ));
+        assert!(!fits(MAX_EXTENDERS + 1, 0));
+        assert!(fits(0, PinSet::CAPACITY));
+        assert!(!fits(0, PinSet::CAPACITY + 1));
+    }
+}
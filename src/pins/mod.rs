@@ -0,0 +1,687 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Manage I²C and provide a transparent pin interface for both onboard and extender pins.
+//!
+//! MCP23017 extenders are the default, but other chips work too (see [`Chip`]), as well as
+//! MCP23S17 extenders over SPI (see [`Bus`]).
+
+mod bus;
+mod chip;
+mod map;
+mod set;
+
+pub use bus::{Bus, BusError, Mcp23s17Bus};
+pub use chip::{probe, Chip, Extender, Operation, Register};
+pub use map::MAX_EXTENDERS;
+pub use set::PinSet;
+
+use chip::{Failure, Written};
+use embassy_futures::select::{select3, select_array, Either3};
+use embassy_rp::gpio::{AnyPin, Flex, Input, Pull};
+use embassy_time::{Duration, Instant, Timer};
+use map::{AddrMap, PINS_PER_EXTENDER};
+
+/// Single extender address offset of PORTA
+const PORT_A: u8 = 0;
+/// Single extender address offset of PORTB
+const PORT_B: u8 = 8;
+/// Pins that are unsafe as inputs on the MCP23017 (GPA7, GPB7), within an extender.
+const UNSAFE_PINS: [u8; 2] = [PORT_A + 7, PORT_B + 7];
+/// Times a failed extender operation is retried before giving up on the extender.
+const MAX_RETRIES: u32 = 3;
+/// Wait before the first retry. This doubles for each retry.
+const RETRY_BACKOFF_US: u64 = 100;
+/// Time between attempts to bring back an extender that was given up on.
+const REVIVE_INTERVAL_MS: u64 = 1000;
+
+/// GPIO extender pin
+struct ExtendedPin {
+    /// Index of extender being used
+    ext_id: usize,
+    /// Pin number in the extender's addressing scheme
+    loc_pin: u8,
+}
+
+enum TransparentPin {
+    /// On-board GPIO (this is an index into `TransparentPins::pins` not the Pico numbering)
+    Onboard(usize),
+    /// Extender pin
+    Extended(ExtendedPin),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// No pin has this address.
+    InvalidPin(u8),
+    /// The chip an extender pin (by address) is on can't do this pull.
+    UnsupportedPull { addr: u8, chip: Chip, pull: Pull },
+    /// Bus error while talking to an extender.
+    Extender {
+        /// Index of the extender, in the order given to [`TransparentPins::new`].
+        index: usize,
+        /// Address of the extender on the bus.
+        addr: u8,
+        /// What was being done.
+        op: Operation,
+        err: BusError,
+    },
+    /// The amount of extenders found by [`probe`] is not the amount expected.
+    ExtenderCount(usize),
+    /// A device answers at an extender's address, but it isn't the chip given for it.
+    WrongChip { index: usize, addr: u8, chip: Chip },
+}
+
+// written by hand because `Pull` can't be formatted with defmt
+impl defmt::Format for Error {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self {
+            Error::InvalidPin(addr) => defmt::write!(f, "InvalidPin({})", addr),
+            Error::UnsupportedPull { addr, chip, pull } => defmt::write!(
+                f,
+                "UnsupportedPull {{ addr: {}, chip: {}, pull: {} }}",
+                addr,
+                chip,
+                defmt::Debug2Format(pull)
+            ),
+            Error::Extender {
+                index,
+                addr,
+                op,
+                err,
+            } => defmt::write!(
+                f,
+                "Extender {{ index: {}, addr: {:#x}, op: {}, err: {} }}",
+                index,
+                addr,
+                op,
+                err
+            ),
+            Error::ExtenderCount(n) => defmt::write!(f, "ExtenderCount({})", n),
+            Error::WrongChip { index, addr, chip } => defmt::write!(
+                f,
+                "WrongChip {{ index: {}, addr: {:#x}, chip: {} }}",
+                index,
+                addr,
+                chip
+            ),
+        }
+    }
+}
+
+/// Operation on a single extender, which is retried on errors.
+#[derive(Clone, Copy)]
+enum ExtOp {
+    /// Set the chip up again, and write all its registers.
+    Init,
+    /// Write pending changes.
+    Sync,
+    /// Read all pins.
+    Read,
+    /// Set up interrupts on some pins.
+    ArmInterrupt(u16),
+    /// Check whether the chip was reset (1 if it was).
+    CheckReset,
+}
+
+/// Error statistics of an extender.
+#[derive(Clone, Copy, Default)]
+struct Health {
+    /// Errors since startup, including the ones that were retried successfully.
+    errors: u32,
+    /// Moment the extender was given up on, or last tried again after that.
+    failed: Option<Instant>,
+}
+
+/// Range of pins that can be iterated over
+#[derive(Clone, Copy)]
+pub struct PinCollection {
+    /// Number of total usable pins.
+    n_usable: usize,
+}
+
+impl IntoIterator for PinCollection {
+    type Item = u8;
+    type IntoIter = core::ops::Range<u8>;
+    fn into_iter(self) -> Self::IntoIter {
+        0..(self.n_usable as u8)
+    }
+}
+
+/// "Transparent pins" to consistently interface with GPIO extenders + onboard GPIO ports.
+///
+/// There are `N_EXT` extenders (up to [`MAX_EXTENDERS`]) and `N_ONBOARD` pins driven directly by
+/// the board. Pin values are given as a [`PinSet`], indexed by address.
+///
+/// Each pin has a number: extender A is 0-15, extender B is 16-31, and so on, then all the onboard
+/// pins. Port A is in the lower byte and port B is in the upper byte of each extender range (see
+/// [`TransparentPins::extender_pin`] and [`TransparentPins::onboard_pin`]).
+///
+/// Some pins can be left out, e.g. because of broken traces, or because they drive LEDs, by not
+/// including them in the `enabled` set given to the constructor. Disabled extender pins are set
+/// as outputs, and disabled onboard pins are left alone. The enabled pins are then given
+/// addresses in order of pin number, with no gaps: this is the single addressing scheme used for
+/// all the pins this interface manages. The exact pins each address refers to are not supposed to
+/// be important.
+///
+/// The MCP23017 is known to have two defective pins, GPA7 and GPB7. These can not be set as inputs
+/// without risks of weird behaviour. [`TransparentPins::safe_pins`] leaves them out on the
+/// MCP23017 extenders; other chips don't have this problem.
+///
+/// Changes to extender pins are batched: they are only written when the pins are next read, or on
+/// [`TransparentPins::flush`]. Only registers that changed are written.
+///
+/// Bus errors are retried, with a recovery of the bus in between (see [`Bus::recover`]), and the
+/// extender is set up again in case it was reset. An extender that keeps failing is given up on:
+/// its pins read high (so keys on it go silent), writes to it are skipped, and it is tried again
+/// every second. Bus errors are therefore only returned by [`TransparentPins::new`], when an
+/// extender can't be set up at all.
+///
+/// If the extenders' interrupt outputs are wired to a pin (see
+/// [`TransparentPins::set_interrupt_pin`]), [`TransparentPins::wait_for_low`] can wait for input
+/// pins to go low without polling the bus.
+pub struct TransparentPins<const N_EXT: usize, const N_ONBOARD: usize> {
+    extenders: [Extender; N_EXT],
+    /// What was last written to each extender.
+    written: [Written; N_EXT],
+    health: [Health; N_EXT],
+    onboard_pins: [Flex<'static, AnyPin>; N_ONBOARD],
+    /// Input/output state of each pin. 1 bit is input, 0 bit is output.
+    io_state: PinSet,
+    /// Output level of each pin. 1 bit is high.
+    out_state: PinSet,
+    /// Pull-up state of each pin. 1 bit is on.
+    pull_state: PinSet,
+    bus: Bus,
+    /// Onboard pin the extenders' interrupt outputs are wired to (active low).
+    int_pin: Option<Input<'static, AnyPin>>,
+    /// Pins (by address) the extenders' interrupts were last set up for.
+    int_armed: Option<PinSet>,
+    /// Pin number of each address.
+    addrs: AddrMap,
+    /// Iterable over all usable pins
+    pub pins: PinCollection,
+}
+
+/// Helper to define the onboard pins in [`TransparentPins`]
+#[macro_export]
+macro_rules! pin_array {
+    ($($pin: expr),*) => {
+        [$($pin.into(),)*]
+    }
+}
+
+impl<const N_EXT: usize, const N_ONBOARD: usize> TransparentPins<N_EXT, N_ONBOARD> {
+    /// Number of total extended pins
+    const N_EXTENDED_PINS: usize = PINS_PER_EXTENDER * N_EXT;
+
+    /// Fails to compile if there are too many pins.
+    const CHECK_SIZE: () = assert!(
+        map::fits(N_EXT, N_ONBOARD),
+        "too many pins for TransparentPins"
+    );
+
+    /// Get amount of usable pins. Transparent pins all have an address from `0..n_usable_pins()`.
+    pub fn n_usable_pins(&self) -> usize {
+        self.pins.n_usable
+    }
+
+    /// Number of a pin on an extender (by index, pin 0-15).
+    pub const fn extender_pin(ext: usize, pin: u8) -> u8 {
+        map::extender_pin(ext, pin)
+    }
+
+    /// Number of an onboard pin (by index in the array given to the constructor).
+    pub const fn onboard_pin(idx: usize) -> u8 {
+        map::onboard_pin(N_EXT, idx)
+    }
+
+    /// Every pin, to enable all of them.
+    pub fn all_pins() -> PinSet {
+        PinSet::first(Self::N_EXTENDED_PINS + N_ONBOARD)
+    }
+
+    /// Every pin except GPA7 and GPB7 on each MCP23017, for the extenders given to the
+    /// constructor.
+    pub fn safe_pins(extenders: &[Extender; N_EXT]) -> PinSet {
+        let mut ret = Self::all_pins();
+        for (i, ext) in extenders.iter().enumerate() {
+            if ext.chip != Chip::Mcp23017 {
+                continue;
+            }
+            for pin in UNSAFE_PINS {
+                ret.remove(Self::extender_pin(i, pin));
+            }
+        }
+        ret
+    }
+
+    /// Transform an address into a pin number, taking into account pins that aren't enabled.
+    fn addr_to_pin(&self, addr: u8) -> Result<u8, Error> {
+        self.addrs.pin(addr).ok_or(Error::InvalidPin(addr))
+    }
+
+    /// Get a pin by its pin number.
+    ///
+    /// This is NOT by the transparent address.
+    fn get_pin(&self, pin: u8) -> Result<TransparentPin, Error> {
+        if pin as usize >= Self::N_EXTENDED_PINS + N_ONBOARD {
+            return Err(Error::InvalidPin(pin));
+        }
+        if pin < (Self::N_EXTENDED_PINS as u8) {
+            let ext_id = (pin as usize) / PINS_PER_EXTENDER;
+            let loc_pin = pin % (PINS_PER_EXTENDER as u8);
+            Ok(TransparentPin::Extended(ExtendedPin { ext_id, loc_pin }))
+        } else {
+            Ok(TransparentPin::Onboard(
+                pin as usize - Self::N_EXTENDED_PINS,
+            ))
+        }
+    }
+
+    /// New function.
+    ///
+    /// `bus` is the I²C peripheral, or an [`Mcp23s17Bus`]. `extenders` are the chips on it, e.g.
+    /// `[Extender::mcp23017(0x20), Extender::mcp23017(0x27)]`. Their enabled pins are all set to
+    /// inputs, without pull-ups.
+    ///
+    /// `enabled` are the pins (by number) that get an address, e.g. [`TransparentPins::safe_pins`].
+    ///
+    /// Every extender is checked first like [`probe`] does. If one doesn't answer, this returns
+    /// [`Error::Extender`], and if it isn't the right chip, [`Error::WrongChip`], so that wiring
+    /// faults don't just look like dead keys. The same goes for extenders that can't be set up.
+    pub async fn new(
+        bus: impl Into<Bus>,
+        extenders: [Extender; N_EXT],
+        pins: [AnyPin; N_ONBOARD],
+        enabled: PinSet,
+    ) -> Result<Self, Error> {
+        let () = Self::CHECK_SIZE;
+        let mut bus = bus.into();
+        for (index, ext) in extenders.iter().enumerate() {
+            let (addr, chip) = (ext.addr, ext.chip);
+            match ext.verify(&mut bus).await {
+                Ok(true) => {}
+                Ok(false) => return Err(Error::WrongChip { index, addr, chip }),
+                Err(err) => {
+                    let op = Operation::Probe;
+                    return Err(Error::Extender {
+                        index,
+                        addr,
+                        op,
+                        err,
+                    });
+                }
+            }
+        }
+        let enabled = enabled & Self::all_pins();
+        let addrs = AddrMap::new(&enabled);
+        // disabled extender pins are outputs
+        let mut io_state = enabled;
+        for idx in 0..N_ONBOARD {
+            io_state.insert(Self::onboard_pin(idx));
+        }
+
+        let mut ret = TransparentPins {
+            extenders,
+            written: [Written::default(); N_EXT],
+            health: [Health::default(); N_EXT],
+            io_state,
+            out_state: PinSet::new(),
+            pull_state: PinSet::new(),
+            onboard_pins: pins.map(Flex::new),
+            bus,
+            int_pin: None,
+            int_armed: None,
+            pins: PinCollection {
+                n_usable: addrs.n_usable(),
+            },
+            addrs,
+        };
+        for i in 0..N_EXT {
+            ret.retry_op(i, ExtOp::Init).await?;
+        }
+        defmt::debug!("TransparentPins: {} usable pins", ret.pins.n_usable);
+        Ok(ret)
+    }
+
+    /// Like [`TransparentPins::new`], but the extenders are found by probing the bus for chips of
+    /// one kind (see [`probe`]). There must be exactly `N_EXT` of them; they are used in order of
+    /// address.
+    pub async fn new_probed(
+        bus: impl Into<Bus>,
+        chip: Chip,
+        pins: [AnyPin; N_ONBOARD],
+        enabled: PinSet,
+    ) -> Result<Self, Error> {
+        let mut bus = bus.into();
+        let found = probe(&mut bus, chip).await;
+        let extenders = found
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::ExtenderCount(found.len()))?;
+        Self::new(bus, extenders, pins, enabled).await
+    }
+
+    /// Write all pins from a set of the pins that are high.
+    pub fn write_all(&mut self, val: PinSet) -> Result<(), Error> {
+        defmt::trace!("write_all: called with val {}", val);
+        for addr in self.pins {
+            let pin_n = self.addr_to_pin(addr)?;
+            let level = val.contains(addr);
+            match self.get_pin(pin_n)? {
+                TransparentPin::Onboard(p) => self.onboard_pins[p].set_level(level.into()),
+                // written on the next read or flush
+                TransparentPin::Extended(_) => self.out_state.set(pin_n, level),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the pin state of an extender, skipping what didn't change.
+    async fn sync_ext(&mut self, i: usize) -> Result<(), Failure> {
+        let word = |set: &PinSet| set.bits(i * PINS_PER_EXTENDER, PINS_PER_EXTENDER) as u16;
+        let (dir, pull, out) = (
+            word(&self.io_state),
+            word(&self.pull_state),
+            word(&self.out_state),
+        );
+        self.extenders[i]
+            .sync(&mut self.bus, &mut self.written[i], dir, pull, out)
+            .await
+    }
+
+    /// Run an operation on an extender once.
+    async fn run_op(&mut self, i: usize, op: ExtOp) -> Result<u16, Error> {
+        let ext = self.extenders[i];
+        let res = self.try_op(i, op).await;
+        res.map_err(|Failure { op, err }| Error::Extender {
+            index: i,
+            addr: ext.addr,
+            op,
+            err,
+        })
+    }
+
+    /// Like [`TransparentPins::run_op`], with errors that don't say which extender it was.
+    async fn try_op(&mut self, i: usize, op: ExtOp) -> Result<u16, Failure> {
+        let ext = self.extenders[i];
+        match op {
+            ExtOp::Init => {
+                ext.init(&mut self.bus, &mut self.written[i]).await?;
+                self.int_armed = None;
+                self.sync_ext(i).await?;
+                Ok(0)
+            }
+            ExtOp::Sync => {
+                self.sync_ext(i).await?;
+                Ok(0)
+            }
+            ExtOp::Read => ext.read(&mut self.bus).await,
+            ExtOp::ArmInterrupt(pins) => {
+                ext.arm_interrupt(&mut self.bus, pins).await?;
+                Ok(0)
+            }
+            ExtOp::CheckReset => Ok(ext.is_reset(&mut self.bus, &self.written[i]).await? as u16),
+        }
+    }
+
+    /// Run an operation on an extender, retrying on errors. Returns `None` if the extender was
+    /// given up on.
+    async fn ext_op(&mut self, i: usize, op: ExtOp) -> Option<u16> {
+        if let Some(since) = self.health[i].failed {
+            if since.elapsed() < Duration::from_millis(REVIVE_INTERVAL_MS) {
+                return None;
+            }
+            self.health[i].failed = Some(Instant::now());
+            if self.run_op(i, ExtOp::Init).await.is_err() {
+                return None;
+            }
+            self.health[i].failed = None;
+            defmt::info!("extender {} ({:#x}) is back", i, self.extenders[i].addr);
+            log::info!("extender {} ({:#x}) is back", i, self.extenders[i].addr);
+        }
+        self.retry_op(i, op).await.ok()
+    }
+
+    /// Run an operation on an extender, retrying on errors. Once the retries are used up, the
+    /// extender is given up on and the last error is returned.
+    async fn retry_op(&mut self, i: usize, op: ExtOp) -> Result<u16, Error> {
+        let mut res = self.run_op(i, op).await;
+        for attempt in 0..MAX_RETRIES {
+            let Err(err) = &res else {
+                break;
+            };
+            self.health[i].errors += 1;
+            defmt::warn!("{} (attempt {})", err, attempt);
+            self.bus.recover().await;
+            Timer::after_micros(RETRY_BACKOFF_US << attempt).await;
+            // the chip may have been reset, e.g. by a brownout
+            res = match self.run_op(i, ExtOp::Init).await {
+                Ok(_) => self.run_op(i, op).await,
+                Err(err) => Err(err),
+            };
+        }
+
+        if let Err(err) = &res {
+            self.health[i].errors += 1;
+            self.health[i].failed = Some(Instant::now());
+            defmt::error!("giving up on extender, its pins are disabled: {}", err);
+            log::error!("giving up on extender, its pins are disabled: {:?}", err);
+        }
+        res
+    }
+
+    /// Errors on an extender (by index) since startup, including the ones that were retried
+    /// successfully.
+    pub fn error_count(&self, ext: usize) -> u32 {
+        self.health[ext].errors
+    }
+
+    /// Whether an extender (by index) was given up on because of errors.
+    pub fn is_failed(&self, ext: usize) -> bool {
+        self.health[ext].failed.is_some()
+    }
+
+    /// Check that the extenders weren't reset (e.g. by a brownout), and set them up again if so.
+    /// Interrupts are armed again on the next wait. Extenders that were given up on are also
+    /// retried.
+    pub async fn check_extenders(&mut self) {
+        for i in 0..N_EXT {
+            if self.ext_op(i, ExtOp::CheckReset).await == Some(1) {
+                self.health[i].errors += 1;
+                defmt::warn!("extender {} ({:#x}) was reset", i, self.extenders[i].addr);
+                log::warn!("extender {} ({:#x}) was reset", i, self.extenders[i].addr);
+                self.ext_op(i, ExtOp::Init).await;
+            }
+        }
+    }
+
+    /// Write pending changes to the extenders.
+    ///
+    /// Extenders that fail are given up on (see [`TransparentPins::is_failed`]) rather than
+    /// returning an error.
+    pub async fn flush(&mut self) {
+        for i in 0..N_EXT {
+            self.ext_op(i, ExtOp::Sync).await;
+        }
+    }
+
+    /// Split some pins (by address) into the raw pins of each extender.
+    fn ext_pins(&self, addrs: &PinSet) -> Result<[u16; N_EXT], Error> {
+        let mut ret = [0; N_EXT];
+        for addr in addrs.iter() {
+            if let TransparentPin::Extended(p) = self.get_pin(self.addr_to_pin(addr)?)? {
+                ret[p.ext_id] |= 1 << p.loc_pin;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Read all pins into the set of pins that are high.
+    pub async fn read_all(&mut self) -> Result<PinSet, Error> {
+        self.read_pins(&PinSet::first(self.n_usable_pins())).await
+    }
+
+    /// Read some pins (by address) into the set of pins that are high.
+    ///
+    /// Only the extenders that hold these pins are read. Pins on other extenders are reported high,
+    /// like unconnected inputs.
+    pub async fn read_pins(&mut self, addrs: &PinSet) -> Result<PinSet, Error> {
+        defmt::trace!("read_pins: called");
+        self.flush().await;
+        let ext_pins = self.ext_pins(addrs)?;
+        // by pin number
+        let mut levels = PinSet::new();
+        for (i, pins) in ext_pins.iter().enumerate() {
+            let read_val = if *pins != 0 {
+                // failed extenders read like unconnected inputs too
+                self.ext_op(i, ExtOp::Read).await.unwrap_or(0xffff)
+            } else {
+                0xffff
+            };
+            levels.set_bits(i * PINS_PER_EXTENDER, PINS_PER_EXTENDER, read_val as u64);
+        }
+        for (idx, pin) in self.onboard_pins.iter().enumerate() {
+            levels.set(Self::onboard_pin(idx), pin.is_high());
+        }
+
+        Ok(self.addrs.pins_to_addrs(&levels))
+    }
+
+    /// Set the pull on an individual pin (0-index).
+    ///
+    /// Note: extender pins do not support pull-down, and only the MCP23017 can turn pull-ups off.
+    /// These return [`Error::UnsupportedPull`].
+    /// Like other changes to extender pins, this is written on the next read or flush.
+    pub fn set_pull(&mut self, addr: u8, pull: Pull) -> Result<(), Error> {
+        let pin_n = self.addr_to_pin(addr)?;
+        let pin = self.get_pin(pin_n)?;
+        match pin {
+            TransparentPin::Onboard(p) => {
+                self.onboard_pins[p].set_pull(pull);
+            }
+            TransparentPin::Extended(p) => {
+                let ext = self.extenders[p.ext_id];
+                if !ext.supports_pull(pull) {
+                    let chip = ext.chip;
+                    return Err(Error::UnsupportedPull { addr, chip, pull });
+                }
+                self.pull_state.set(pin_n, matches!(pull, Pull::Up));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets a pin as an input.
+    pub fn set_input(&mut self, addr: u8) -> Result<(), Error> {
+        let pin_n = self.addr_to_pin(addr)?;
+        let pin = self.get_pin(pin_n)?;
+        self.io_state.set(pin_n, true);
+        match pin {
+            TransparentPin::Onboard(p) => self.onboard_pins[p].set_as_input(),
+            // written on the next read or flush
+            TransparentPin::Extended(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Sets a pin as an output.
+    pub fn set_output(&mut self, addr: u8) -> Result<(), Error> {
+        let pin_n = self.addr_to_pin(addr)?;
+        let pin = self.get_pin(pin_n)?;
+        self.io_state.set(pin_n, false);
+        match pin {
+            TransparentPin::Onboard(p) => self.onboard_pins[p].set_as_output(),
+            // written on the next read or flush
+            TransparentPin::Extended(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Use an onboard pin for the extenders' interrupt outputs.
+    ///
+    /// All the interrupt outputs (both INTA and INTB on MCP23017 chips) are open-drain, and can be
+    /// wired together to this pin, which has a pull-up.
+    pub fn set_interrupt_pin(&mut self, pin: AnyPin) {
+        self.int_pin = Some(Input::new(pin, Pull::Up));
+        self.int_armed = None;
+    }
+
+    /// Whether an interrupt pin was set with [`TransparentPins::set_interrupt_pin`].
+    pub fn has_interrupt_pin(&self) -> bool {
+        self.int_pin.is_some()
+    }
+
+    /// Wait until any of some input pins (by address) is low, or for the timeout.
+    ///
+    /// Returns true if a pin is low. Extender pins are only watched through the interrupt pin;
+    /// without one, only onboard pins are watched. The bus is not polled while waiting.
+    pub async fn wait_for_low(&mut self, addrs: &PinSet, timeout: Duration) -> Result<bool, Error> {
+        self.flush().await;
+        let ext_pins = self.ext_pins(addrs)?;
+        if self.int_pin.is_some() {
+            if self.int_armed != Some(*addrs) {
+                // extenders with no watched pins only need disarming if they were armed before
+                // (or might have been)
+                let armed = match self.int_armed {
+                    Some(armed) => self.ext_pins(&armed)?,
+                    None => [u16::MAX; N_EXT],
+                };
+                for (i, pins) in ext_pins.iter().enumerate() {
+                    // failed extenders are armed again when they come back (see `ExtOp::Init`)
+                    if self.is_failed(i) || (*pins == 0 && armed[i] == 0) {
+                        continue;
+                    }
+                    self.ext_op(i, ExtOp::ArmInterrupt(*pins)).await;
+                }
+                self.int_armed = Some(*addrs);
+            }
+            // reading clears interrupts that are no longer relevant
+            for (i, pins) in ext_pins.iter().enumerate() {
+                if *pins != 0 && !self.is_failed(i) {
+                    self.ext_op(i, ExtOp::Read).await;
+                }
+            }
+        }
+
+        let watched_pins = self.addrs.addrs_to_pins(addrs);
+        let mut idx = 0;
+        let onboard = self.onboard_pins.each_mut().map(|pin| {
+            let watched = watched_pins.contains(Self::onboard_pin(idx));
+            idx += 1;
+            async move {
+                if watched {
+                    pin.wait_for_low().await
+                } else {
+                    core::future::pending().await
+                }
+            }
+        });
+        let int_pin = &mut self.int_pin;
+        let int = async {
+            match int_pin {
+                Some(pin) => pin.wait_for_low().await,
+                None => core::future::pending().await,
+            }
+        };
+        let woke = select3(int, select_array(onboard), Timer::after(timeout)).await;
+        Ok(!matches!(woke, Either3::Third(_)))
+    }
+}
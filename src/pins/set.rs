@@ -0,0 +1,253 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Bitset of pins, for when there are too many for a `u64`.
+
+use core::fmt::{self, Write};
+use core::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign};
+
+const WORDS: usize = 3;
+
+/// Set of pins, one bit per pin number.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct PinSet {
+    words: [u64; WORDS],
+}
+
+impl PinSet {
+    /// Highest amount of pins that fit in the set.
+    pub const CAPACITY: usize = 64 * WORDS;
+
+    /// Empty set.
+    pub const fn new() -> Self {
+        PinSet { words: [0; WORDS] }
+    }
+
+    /// Set with pins `0..n`.
+    pub fn first(n: usize) -> Self {
+        let mut ret = Self::new();
+        for (i, word) in ret.words.iter_mut().enumerate() {
+            let bits = n.saturating_sub(64 * i).min(64);
+            *word = if bits == 64 {
+                u64::MAX
+            } else {
+                (1 << bits) - 1
+            };
+        }
+        ret
+    }
+
+    /// Pin is in the set. Pins past the capacity never are.
+    pub fn contains(&self, pin: u8) -> bool {
+        let pin = pin as usize;
+        pin < Self::CAPACITY && self.words[pin / 64] & (1 << (pin % 64)) != 0
+    }
+
+    /// Add or remove a pin.
+    ///
+    /// Panics if the pin is past the capacity.
+    pub fn set(&mut self, pin: u8, value: bool) {
+        let pin = pin as usize;
+        if value {
+            self.words[pin / 64] |= 1 << (pin % 64);
+        } else {
+            self.words[pin / 64] &= !(1 << (pin % 64));
+        }
+    }
+
+    pub fn insert(&mut self, pin: u8) {
+        self.set(pin, true)
+    }
+
+    pub fn remove(&mut self, pin: u8) {
+        self.set(pin, false)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|w| *w == 0)
+    }
+
+    /// Pins in the set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..Self::CAPACITY as u8).filter(|pin| self.contains(*pin))
+    }
+
+    /// Read `len` bits (at most 64) starting at some pin, as an integer.
+    pub fn bits(&self, start: usize, len: usize) -> u64 {
+        let mut ret = 0;
+        for i in (0..len).rev() {
+            ret = (ret << 1) | self.contains((start + i) as u8) as u64;
+        }
+        ret
+    }
+
+    /// Write `len` bits (at most 64) starting at some pin, from an integer.
+    pub fn set_bits(&mut self, start: usize, len: usize, val: u64) {
+        for i in 0..len {
+            self.set((start + i) as u8, (val >> i) & 1 != 0);
+        }
+    }
+}
+
+macro_rules! bit_op {
+    ($trait: ident, $method: ident, $assign_trait: ident, $assign_method: ident, $op: tt) => {
+        impl $trait for PinSet {
+            type Output = PinSet;
+            fn $method(mut self, rhs: PinSet) -> PinSet {
+                self.$assign_method(rhs);
+                self
+            }
+        }
+
+        impl $assign_trait for PinSet {
+            fn $assign_method(&mut self, rhs: PinSet) {
+                for (a, b) in self.words.iter_mut().zip(rhs.words) {
+                    *a $op b;
+                }
+            }
+        }
+    };
+}
+
+bit_op!(BitAnd, bitand, BitAndAssign, bitand_assign, &=);
+bit_op!(BitOr, bitor, BitOrAssign, bitor_assign, |=);
+bit_op!(BitXor, bitxor, BitXorAssign, bitxor_assign, ^=);
+
+/// Writes the set as binary, with the highest pin first. The width is the amount of pins shown.
+impl fmt::Binary for PinSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = f
+            .width()
+            .unwrap_or_else(|| self.iter().last().map_or(1, |pin| pin as usize + 1));
+        for pin in (0..len).rev() {
+            f.write_char(if self.contains(pin as u8) { '1' } else { '0' })?;
+        }
+        Ok(())
+    }
+}
+
+impl defmt::Format for PinSet {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{:#x}", self.words)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first() {
+        assert!(PinSet::first(0).is_empty());
+        for n in [1, 63, 64, 65, 127, 128, 129, PinSet::CAPACITY] {
+            let set = PinSet::first(n);
+            assert!(set.contains(n as u8 - 1), "{}", n);
+            assert!(!set.contains(n as u8), "{}", n);
+            assert_eq!(set.iter().count(), n);
+        }
+        // past the capacity, every pin
+        assert_eq!(
+            PinSet::first(PinSet::CAPACITY + 10),
+            PinSet::first(PinSet::CAPACITY)
+        );
+    }
+
+    #[test]
+    fn set_and_contains() {
+        let mut set = PinSet::new();
+        for pin in [0, 63, 64, 127, 128, 191] {
+            set.insert(pin);
+            assert!(set.contains(pin));
+        }
+        assert_eq!(set.iter().count(), 6);
+        set.remove(64);
+        assert!(!set.contains(64));
+        assert!(set.contains(63));
+        // past the capacity
+        assert!(!set.contains(192));
+        assert!(!set.contains(255));
+    }
+
+    #[test]
+    fn bits_within_a_word() {
+        let mut set = PinSet::new();
+        set.set_bits(16, 16, 0xa55a);
+        assert_eq!(set.bits(16, 16), 0xa55a);
+        assert_eq!(set.bits(0, 16), 0);
+        assert_eq!(set.bits(32, 16), 0);
+        assert!(set.contains(17));
+        assert!(!set.contains(16));
+    }
+
+    #[test]
+    fn bits_across_words() {
+        for start in [56, 60, 63, 120, 127] {
+            let mut set = PinSet::new();
+            set.set_bits(start, 16, 0xbeef);
+            assert_eq!(set.bits(start, 16), 0xbeef, "{}", start);
+            assert_eq!(set.iter().count(), 0xbeefu16.count_ones() as usize);
+            assert_eq!(set.iter().next(), Some(start as u8));
+            assert_eq!(set.iter().last(), Some(start as u8 + 15));
+        }
+    }
+
+    #[test]
+    fn full_word_bits() {
+        let mut set = PinSet::new();
+        set.set_bits(64, 64, u64::MAX);
+        assert_eq!(set.bits(64, 64), u64::MAX);
+        assert_eq!(set.bits(0, 64), 0);
+        assert_eq!(set.bits(128, 64), 0);
+        assert_eq!(set.bits(32, 64), 0xffff_ffff_0000_0000);
+        assert_eq!(set.bits(100, 64), 0x0fff_ffff);
+
+        set.set_bits(96, 64, 0);
+        assert_eq!(set.bits(64, 64), 0xffff_ffff);
+        assert_eq!(set.bits(176, 16), 0);
+    }
+
+    #[test]
+    fn set_bits_clears() {
+        let mut set = PinSet::first(PinSet::CAPACITY);
+        set.set_bits(60, 8, 0x0f);
+        assert_eq!(set.bits(56, 16), 0xf0ff);
+        assert_eq!(set.iter().count(), PinSet::CAPACITY - 4);
+    }
+
+    #[test]
+    fn bit_ops() {
+        let a = PinSet::first(100);
+        let mut b = PinSet::new();
+        b.insert(10);
+        b.insert(150);
+        assert_eq!((a & b).iter().count(), 1);
+        assert_eq!((a | b).iter().count(), 101);
+        assert_eq!((a ^ b).iter().count(), 100);
+        assert!(!(a ^ b).contains(10));
+    }
+
+    #[test]
+    fn binary() {
+        let mut set = PinSet::new();
+        set.insert(0);
+        set.insert(3);
+        assert_eq!(format!("{:b}", set), "1001");
+        assert_eq!(format!("{:6b}", set), "001001");
+        assert_eq!(format!("{:b}", PinSet::new()), "0");
+    }
+}
@@ -0,0 +1,105 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Command serial port.
+//!
+//! This is a second USB serial port (the first one is the log), which takes single-character
+//! commands:
+//!
+//! - `r`: send the looper's recording as a Standard MIDI File.
+//! - `h`: send everything played in the last few minutes (see [`history`]) as a Standard MIDI
+//!   File.
+//! - `c`: clear the log of what was played.
+//! - `b`: reboot into BOOTSEL mode, to update the firmware.
+//!
+//! For example, `cat /dev/ttyACM1 > rec.mid & printf r > /dev/ttyACM1`.
+
+use crate::bootsel;
+use crate::history;
+use crate::looper;
+use crate::midi::Disconnected;
+use embassy_rp::usb::{Driver, Instance};
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+
+/// Handle commands until connection breaks
+pub async fn serial_session<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+    loop {
+        let n = class.read_packet(&mut buf).await?;
+        for byte in buf[..n].iter() {
+            match byte {
+                b'r' => send_recording(class).await?,
+                b'h' => send_history(class).await?,
+                b'c' => history::clear(),
+                b'b' => bootsel::reboot_to_bootsel().await,
+                b'\r' | b'\n' => {}
+                _ => defmt::warn!("serial: unknown command {}", byte),
+            }
+        }
+    }
+}
+
+/// Send a file over the serial port, produced by `read` a packet at a time.
+async fn send_file<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    mut read: impl FnMut(&mut [u8]) -> usize,
+) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+    let mut last = 0;
+    loop {
+        let n = read(&mut buf);
+        if n == 0 {
+            break;
+        }
+        class.write_packet(&buf[..n]).await?;
+        last = n;
+    }
+    if last == buf.len() {
+        // a full packet doesn't end the transfer
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+async fn send_recording<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+) -> Result<(), Disconnected> {
+    let Some(mut smf) = looper::start_export() else {
+        defmt::warn!("serial: can not send recording while recording");
+        return Ok(());
+    };
+    defmt::info!("serial: sending recording ({} bytes)", smf.file_len());
+    let res = send_file(class, |buf| looper::read_smf(&mut smf, buf)).await;
+    looper::end_export();
+    res
+}
+
+async fn send_history<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+) -> Result<(), Disconnected> {
+    let Some(mut smf) = history::start_export() else {
+        defmt::warn!("serial: nothing played yet");
+        return Ok(());
+    };
+    defmt::info!("serial: sending history ({} bytes)", smf.file_len());
+    let res = send_file(class, |buf| history::read_smf(&mut smf, buf)).await;
+    history::end_export();
+    res
+}
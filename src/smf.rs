@@ -0,0 +1,301 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Standard MIDI File (Type 0) writer.
+//!
+//! Files are produced a few bytes at a time with [`Encoder::read`], so that they can be streamed
+//! without holding the whole file in memory. The events themselves stay wherever they are stored
+//! (e.g. the looper's buffer), and are looked up by index as the file is written.
+
+/// Timestamped MIDI channel message.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Event {
+    /// Time in milliseconds, from an arbitrary origin.
+    pub time: u32,
+    /// Status and data bytes. Unused data bytes are ignored.
+    pub msg: [u8; 3],
+}
+
+impl Event {
+    /// Length of the message in bytes, including the status.
+    pub fn msg_len(&self) -> usize {
+        match self.msg[0] & 0xf0 {
+            // program change, channel pressure
+            0xc0 | 0xd0 => 2,
+            _ => 3,
+        }
+    }
+}
+
+/// Ticks per quarter note.
+///
+/// With the tempo set to 500000 µs per quarter note, a tick is a millisecond, so event times can
+/// be written as-is.
+const DIVISION: u16 = 500;
+/// Microseconds per quarter note (120 BPM).
+const TEMPO: u32 = 500_000;
+
+/// Header chunk for a Type 0 file with one track, without the division.
+const HEADER: &[u8] = b"MThd\0\0\0\x06\0\0\0\x01";
+/// Start of the track chunk, without its length.
+const TRACK: &[u8] = b"MTrk";
+/// Tempo meta-event (delta time, FF 51 03, then the tempo), without the tempo.
+const TEMPO_EVENT: &[u8] = b"\0\xff\x51\x03";
+/// End of track meta-event.
+const END_EVENT: &[u8] = b"\0\xff\x2f\0";
+
+/// Write a variable-length quantity. Returns the amount of bytes used.
+fn vlq(value: u32, out: &mut [u8]) -> usize {
+    let mut n = 1;
+    while n < 5 && value >> (7 * n) != 0 {
+        n += 1;
+    }
+    for (i, byte) in out[..n].iter_mut().enumerate() {
+        // most significant group first, with the continuation bit on all but the last
+        let group = ((value >> (7 * (n - 1 - i))) & 0x7f) as u8;
+        *byte = if i < n - 1 { group | 0x80 } else { group };
+    }
+    n
+}
+
+enum Stage {
+    Header,
+    Events,
+    End,
+}
+
+/// Streaming SMF writer.
+pub struct Encoder {
+    stage: Stage,
+    /// Amount of events in the file.
+    count: usize,
+    /// Next event to write.
+    idx: usize,
+    /// Time of the previous event written.
+    prev: u32,
+    /// Length of the track chunk's data.
+    track_len: u32,
+    /// Bytes of the current piece not yet read out.
+    pending: [u8; 32],
+    pending_len: usize,
+    pending_pos: usize,
+}
+
+impl Encoder {
+    /// Prepare to write some events, in order. Times are relative to `origin`.
+    ///
+    /// The same events must then be given to [`Encoder::read`].
+    pub fn new<'a>(events: impl Iterator<Item = &'a Event>, origin: u32) -> Self {
+        let mut count = 0;
+        let mut prev = origin;
+        let mut track_len = (TEMPO_EVENT.len() + 3 + END_EVENT.len()) as u32;
+        let mut tmp = [0u8; 5];
+        for ev in events {
+            track_len += (vlq(ev.time.saturating_sub(prev), &mut tmp) + ev.msg_len()) as u32;
+            prev = prev.max(ev.time);
+            count += 1;
+        }
+        Encoder {
+            stage: Stage::Header,
+            count,
+            idx: 0,
+            prev: origin,
+            track_len,
+            pending: [0; 32],
+            pending_len: 0,
+            pending_pos: 0,
+        }
+    }
+
+    /// Size of the whole file in bytes.
+    pub fn file_len(&self) -> usize {
+        HEADER.len() + 2 + TRACK.len() + 4 + self.track_len as usize
+    }
+
+    fn set_pending(&mut self, pieces: &[&[u8]]) {
+        self.pending_len = 0;
+        self.pending_pos = 0;
+        for piece in pieces {
+            self.pending[self.pending_len..self.pending_len + piece.len()].copy_from_slice(piece);
+            self.pending_len += piece.len();
+        }
+    }
+
+    /// Prepare the next piece of the file. Returns false when the file is complete.
+    fn next_piece(&mut self, event: &impl Fn(usize) -> Option<Event>) -> bool {
+        match self.stage {
+            Stage::Header => {
+                self.set_pending(&[
+                    HEADER,
+                    &DIVISION.to_be_bytes(),
+                    TRACK,
+                    &self.track_len.to_be_bytes(),
+                    TEMPO_EVENT,
+                    &TEMPO.to_be_bytes()[1..],
+                ]);
+                self.stage = Stage::Events;
+            }
+            Stage::Events => {
+                let ev = if self.idx < self.count {
+                    event(self.idx)
+                } else {
+                    None
+                };
+                match ev {
+                    Some(ev) => {
+                        let mut buf = [0u8; 8];
+                        let n = vlq(ev.time.saturating_sub(self.prev), &mut buf);
+                        buf[n..n + ev.msg_len()].copy_from_slice(&ev.msg[..ev.msg_len()]);
+                        self.set_pending(&[&buf[..n + ev.msg_len()]]);
+                        // never let the time go backwards
+                        self.prev = self.prev.max(ev.time);
+                        self.idx += 1;
+                    }
+                    None => {
+                        // the events must not change while writing (see the users of this
+                        // module), or the track length already written would be wrong
+                        self.set_pending(&[END_EVENT]);
+                        self.stage = Stage::End;
+                    }
+                }
+            }
+            Stage::End => return false,
+        }
+        true
+    }
+
+    /// Write the next bytes of the file into `buf`, looking up events by index with `event`.
+    ///
+    /// Returns the amount of bytes written, which is 0 once the file is complete.
+    pub fn read(&mut self, event: impl Fn(usize) -> Option<Event>, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() {
+            if self.pending_pos == self.pending_len && !self.next_piece(&event) {
+                break;
+            }
+            let take = (self.pending_len - self.pending_pos).min(buf.len() - n);
+            buf[n..n + take]
+                .copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + take]);
+            self.pending_pos += take;
+            n += take;
+        }
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vlq_bytes(value: u32) -> heapless::Vec<u8, 5> {
+        let mut buf = [0u8; 5];
+        let n = vlq(value, &mut buf);
+        heapless::Vec::from_slice(&buf[..n]).unwrap()
+    }
+
+    /// Write a whole file, a few bytes at a time.
+    fn write(events: &[Event], origin: u32) -> (Encoder, heapless::Vec<u8, 256>) {
+        let mut enc = Encoder::new(events.iter(), origin);
+        let mut file = heapless::Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let n = enc.read(|i| events.get(i).copied(), &mut buf);
+            if n == 0 {
+                break;
+            }
+            file.extend_from_slice(&buf[..n]).unwrap();
+        }
+        (enc, file)
+    }
+
+    fn note_on(time: u32, note: u8) -> Event {
+        Event {
+            time,
+            msg: [0x90, note, 100],
+        }
+    }
+
+    #[test]
+    fn vlq_boundaries() {
+        assert_eq!(vlq_bytes(0), [0x00]);
+        assert_eq!(vlq_bytes(0x7f), [0x7f]);
+        assert_eq!(vlq_bytes(0x80), [0x81, 0x00]);
+        assert_eq!(vlq_bytes(0x3fff), [0xff, 0x7f]);
+        assert_eq!(vlq_bytes(0x4000), [0x81, 0x80, 0x00]);
+        assert_eq!(vlq_bytes(0x1f_ffff), [0xff, 0xff, 0x7f]);
+        assert_eq!(vlq_bytes(0x20_0000), [0x81, 0x80, 0x80, 0x00]);
+        assert_eq!(vlq_bytes(0x0fff_ffff), [0xff, 0xff, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn track_length_matches_bytes_written() {
+        // deltas on both sides of the VLQ size boundaries
+        let events = [
+            note_on(0x7f, 60),
+            note_on(0x7f + 0x80, 62),
+            Event {
+                time: 0x7f + 0x80 + 0x3fff,
+                msg: [0xc0, 5, 0],
+            },
+            note_on(0x7f + 0x80 + 0x3fff + 0x4000, 64),
+        ];
+        let (enc, file) = write(&events, 0);
+        assert_eq!(file.len(), enc.file_len());
+        assert_eq!(&file[..4], b"MThd");
+        assert_eq!(&file[14..18], b"MTrk");
+        let track_len = u32::from_be_bytes(file[18..22].try_into().unwrap());
+        assert_eq!(track_len as usize, file.len() - 22);
+        assert_eq!(&file[file.len() - 4..], END_EVENT);
+    }
+
+    #[test]
+    fn events_are_written_with_deltas() {
+        let events = [
+            note_on(1000, 60),
+            Event {
+                time: 1200,
+                msg: [0xd0, 40, 0],
+            },
+        ];
+        let (_, file) = write(&events, 1000);
+        // header, division, track header, tempo
+        let track = &file[22 + 7..];
+        assert_eq!(
+            track,
+            [0x00, 0x90, 60, 100, 0x81, 0x48, 0xd0, 40, 0x00, 0xff, 0x2f, 0x00]
+        );
+    }
+
+    #[test]
+    fn time_never_goes_backwards() {
+        let events = [note_on(500, 60), note_on(400, 62), note_on(600, 64)];
+        let (enc, file) = write(&events, 0);
+        assert_eq!(file.len(), enc.file_len());
+        let track = &file[22 + 7..];
+        assert_eq!(&track[..5], [0x83, 0x74, 0x90, 60, 100]);
+        assert_eq!(&track[5..9], [0x00, 0x90, 62, 100]);
+        assert_eq!(&track[9..13], [0x64, 0x90, 64, 100]);
+    }
+
+    #[test]
+    fn empty_file() {
+        let (enc, file) = write(&[], 0);
+        assert_eq!(file.len(), enc.file_len());
+        assert_eq!(&file[file.len() - 4..], END_EVENT);
+    }
+}
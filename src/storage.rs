@@ -0,0 +1,125 @@
+/*
+    geode-piano
+    Copyright (C) 2024 dogeystamp <dogeystamp@disroot.org>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Persistent storage in the Pico's flash.
+//!
+//! The end of the flash is reserved for data (see `memory.x`), and split into regions for each
+//! user. Call [`init`] with the flash peripheral before using any of them.
+
+use core::cell::RefCell;
+use embassy_rp::flash::{self, Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+
+/// Size of the Pico's flash.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Size of the part reserved for data, at the end of the flash. Must match `memory.x`.
+pub const STORAGE_SIZE: usize = 64 * 1024;
+
+/// Part of the storage, aligned to erase sectors.
+pub struct Region {
+    /// Offset from the start of the storage.
+    offset: u32,
+    size: u32,
+}
+
+impl Region {
+    /// Size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+}
+
+/// Looper recording.
+pub const RECORDING: Region = Region {
+    offset: 0,
+    size: 4 * ERASE_SIZE as u32,
+};
+
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    /// [`init`] was not called.
+    Uninitialized,
+    /// Access goes past the end of the region.
+    OutOfBounds,
+    Flash(flash::Error),
+}
+
+impl From<flash::Error> for Error {
+    fn from(err: flash::Error) -> Self {
+        Error::Flash(err)
+    }
+}
+
+type FlashDriver = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+static FLASH_DRIVER: Mutex<ThreadModeRawMutex, RefCell<Option<FlashDriver>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Take the flash peripheral for storage.
+pub fn init(flash: FLASH) {
+    FLASH_DRIVER.lock(|f| f.replace(Some(Flash::new_blocking(flash))));
+}
+
+/// Run something with the flash driver, and the absolute offset of a range in a region.
+fn with_flash<R>(
+    region: &Region,
+    offset: u32,
+    len: usize,
+    f: impl FnOnce(&mut FlashDriver, u32) -> Result<R, flash::Error>,
+) -> Result<R, Error> {
+    if offset as usize + len > region.size() {
+        return Err(Error::OutOfBounds);
+    }
+    let start = (FLASH_SIZE - STORAGE_SIZE) as u32 + region.offset + offset;
+    FLASH_DRIVER.lock(|flash| match flash.borrow_mut().as_mut() {
+        Some(flash) => Ok(f(flash, start)?),
+        None => Err(Error::Uninitialized),
+    })
+}
+
+/// Unique ID of the flash chip, which identifies the board.
+pub fn unique_id() -> Result<[u8; 8], Error> {
+    let mut id = [0u8; 8];
+    FLASH_DRIVER.lock(|flash| match flash.borrow_mut().as_mut() {
+        Some(flash) => Ok(flash.blocking_unique_id(&mut id)?),
+        None => Err(Error::Uninitialized),
+    })?;
+    Ok(id)
+}
+
+/// Erase a whole region. This must be done before writing to it.
+pub fn erase(region: &Region) -> Result<(), Error> {
+    with_flash(region, 0, region.size(), |flash, start| {
+        flash.blocking_erase(start, start + region.size)
+    })
+}
+
+/// Write data at some offset in a region.
+pub fn write(region: &Region, offset: u32, data: &[u8]) -> Result<(), Error> {
+    with_flash(region, offset, data.len(), |flash, start| {
+        flash.blocking_write(start, data)
+    })
+}
+
+/// Read data at some offset in a region.
+pub fn read(region: &Region, offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+    with_flash(region, offset, buf.len(), |flash, start| {
+        flash.blocking_read(start, buf)
+    })
+}
@@ -32,27 +32,101 @@ DEALINGS IN THE SOFTWARE.
 */
 
 use embassy_futures::join::join;
+use embassy_futures::select::{select, select3, Either3};
 use embassy_rp::{peripherals::USB, usb::Driver};
 
-use crate::midi::midi_session;
+use crate::hid::{hid_session, GAMEPAD_DESCRIPTOR};
+use crate::midi::{self, midi_receive, midi_session, N_CABLES};
+use crate::serial::serial_session;
+use crate::storage;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
 use embassy_usb::class::cdc_acm::CdcAcmClass;
 use embassy_usb::class::cdc_acm::State;
+use embassy_usb::class::hid::{self as usb_hid, HidWriter};
 use embassy_usb::class::midi::MidiClass;
 use embassy_usb::{Builder, Config};
+use portable_atomic::{AtomicBool, Ordering};
+use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+
+/// How the device presents itself over USB.
+#[derive(Clone, Copy)]
+pub struct Identity {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    /// Serial number. If `None`, it is made from the flash chip's unique ID, so that each board
+    /// can be told apart. This needs [`storage::init`] to be called before the USB task starts.
+    pub serial_number: Option<&'static str>,
+}
+
+impl Identity {
+    pub const DEFAULT: Identity = Identity {
+        vid: 0xdead,
+        pid: 0xbeef,
+        manufacturer: "dogeystamp",
+        product: "Geode-Piano MIDI keyboard",
+        serial_number: None,
+    };
+}
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+static WAKEUP: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// The host suspended the bus, e.g. because it is asleep.
+///
+/// MIDI sent meanwhile is thrown away.
+pub fn suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Ask the host to wake up from suspend. This does nothing if the host did not allow remote
+/// wakeup.
+pub fn remote_wakeup() {
+    WAKEUP.signal(());
+}
+
+/// Write the flash chip's unique ID in hex. Falls back to the firmware version.
+fn board_serial(buf: &mut [u8; 16]) -> &str {
+    match storage::unique_id() {
+        Ok(id) => {
+            const HEX: &[u8; 16] = b"0123456789ABCDEF";
+            for (i, byte) in id.iter().enumerate() {
+                buf[2 * i] = HEX[(byte >> 4) as usize];
+                buf[2 * i + 1] = HEX[(byte & 0xf) as usize];
+            }
+            // only ASCII was written
+            core::str::from_utf8(buf).unwrap()
+        }
+        Err(e) => {
+            defmt::warn!("usb: could not read flash ID: {}", e);
+            env!("CARGO_PKG_VERSION")
+        }
+    }
+}
 
 #[embassy_executor::task]
 pub async fn usb_task(
     // remember this is the Driver struct not the trait
     driver: Driver<'static, USB>,
     log_level: log::LevelFilter,
+    identity: Identity,
 ) {
+    let mut serial_buf = [0u8; 16];
+    let serial_number = match identity.serial_number {
+        Some(serial) => serial,
+        None => board_serial(&mut serial_buf),
+    };
+
     // Create embassy-usb Config
-    let mut config = Config::new(0xdead, 0xbeef);
-    config.manufacturer = Some("dogeystamp");
-    config.product = Some("Geode-Piano MIDI keyboard");
-    config.serial_number = Some("0.3.0");
+    let mut config = Config::new(identity.vid, identity.pid);
+    config.manufacturer = Some(identity.manufacturer);
+    config.product = Some(identity.product);
+    config.serial_number = Some(serial_number);
     config.max_power = 100;
     config.max_packet_size_0 = 64;
+    config.supports_remote_wakeup = true;
 
     // Required for windows compatibility.
     // https://developer.nordicsemi.com/nRF_Connect_SDK/doc/1.9.1/kconfig/CONFIG_CDC_ACM_IAD.html#help
@@ -63,12 +137,15 @@ pub async fn usb_task(
 
     // Create embassy-usb DeviceBuilder using the driver and config.
     // It needs some buffers for building the descriptors.
-    let mut config_descriptor = [0; 256];
+    let mut config_descriptor = [0; 512];
     let mut device_descriptor = [0; 256];
     let mut bos_descriptor = [0; 256];
     let mut control_buf = [0; 64];
 
     let mut logger_state = State::new();
+    let mut serial_state = State::new();
+    let mut keyboard_state = usb_hid::State::new();
+    let mut gamepad_state = usb_hid::State::new();
 
     let mut builder = Builder::new(
         driver,
@@ -81,27 +158,86 @@ pub async fn usb_task(
     );
 
     // Create classes on the builder.
-    let mut midi_class = MidiClass::new(&mut builder, 1, 1, 64);
+    // one cable to the host for each of keys, controls and diagnostics (the "in" jacks are the
+    // ones the device sends on), and one cable from the host
+    let midi_class = MidiClass::new(&mut builder, N_CABLES, 1, 64);
     let logger_class = CdcAcmClass::new(&mut builder, &mut logger_state, 64);
     let log_fut = embassy_usb_logger::with_class!(1024, log_level, logger_class);
-
-    // The `MidiClass` can be split into `Sender` and `Receiver`, to be used in separate tasks.
-    // let (sender, receiver) = class.split();
+    let mut serial_class = CdcAcmClass::new(&mut builder, &mut serial_state, 64);
+    let mut keyboard = HidWriter::<_, 8>::new(
+        &mut builder,
+        &mut keyboard_state,
+        usb_hid::Config {
+            report_descriptor: KeyboardReport::desc(),
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: 8,
+        },
+    );
+    let mut gamepad = HidWriter::<_, 4>::new(
+        &mut builder,
+        &mut gamepad_state,
+        usb_hid::Config {
+            report_descriptor: GAMEPAD_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: 4,
+        },
+    );
 
     // Build the builder.
     let mut usb = builder.build();
 
-    // Run the USB device.
-    let usb_fut = usb.run();
+    // Run the USB device, and handle suspend.
+    let usb_fut = async {
+        loop {
+            usb.run_until_suspend().await;
+            defmt::info!("usb: suspended");
+            SUSPENDED.store(true, Ordering::Relaxed);
+            WAKEUP.reset();
+            if let Either3::Second(()) =
+                select3(usb.wait_resume(), WAKEUP.wait(), midi::discard()).await
+            {
+                if let Err(e) = usb.remote_wakeup().await {
+                    defmt::warn!("usb: remote wakeup failed: {}", e);
+                }
+            }
+            SUSPENDED.store(false, Ordering::Relaxed);
+            defmt::info!("usb: resumed");
+        }
+    };
 
+    let (mut midi_sender, mut midi_receiver) = midi_class.split();
     let midi_fut = async {
         loop {
-            midi_class.wait_connection().await;
+            midi_sender.wait_connection().await;
             defmt::info!("Connected");
-            let _ = midi_session(&mut midi_class).await;
+            let _ = select(
+                midi_session(&mut midi_sender),
+                midi_receive(&mut midi_receiver),
+            )
+            .await;
             defmt::info!("Disconnected");
         }
     };
 
-    join(usb_fut, join(log_fut, midi_fut)).await;
+    let serial_fut = async {
+        loop {
+            serial_class.wait_connection().await;
+            let _ = serial_session(&mut serial_class).await;
+        }
+    };
+
+    let hid_fut = async {
+        loop {
+            keyboard.ready().await;
+            let _ = hid_session(&mut keyboard, &mut gamepad).await;
+        }
+    };
+
+    join(
+        usb_fut,
+        join(log_fut, join(midi_fut, join(serial_fut, hid_fut))),
+    )
+    .await;
 }